@@ -1,10 +1,16 @@
 use std::env::current_dir;
 use std::fs::create_dir_all;
 
-use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
+use cosmwasm_schema::{export_schema, export_schema_with_title, remove_schemas, schema_for};
 
-use turnstone::msg::{ExecuteMsg, InstantiateMsg, JobId, QueryMsg};
-use turnstone::state::State;
+use turnstone::error::ContractError;
+use turnstone::msg::{
+    ConsensusMsg, ConsensusSimulation, DepositInfoResponse, DueJobsResponse, ExecuteMsg,
+    InstantiateMsg, JobDepositorsResponse, JobId, JobInfo, JobInfoResponse, ListJobsResponse,
+    MerkleProof, MigrateMsg, QueryMsg, RelayerRewardsResponse, SignedMessage, SigningBytes,
+    SudoMsg, Validator,
+};
+use turnstone::state::Config;
 
 fn main() {
     let mut out_dir = current_dir().unwrap();
@@ -12,9 +18,33 @@ fn main() {
     create_dir_all(&out_dir).unwrap();
     remove_schemas(&out_dir).unwrap();
 
+    export_schema(&schema_for!(Config), &out_dir);
+    export_schema(&schema_for!(ConsensusMsg), &out_dir);
+    export_schema(&schema_for!(ContractError), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
     export_schema(&schema_for!(InstantiateMsg), &out_dir);
     export_schema(&schema_for!(JobId), &out_dir);
+    export_schema(&schema_for!(JobInfo), &out_dir);
+    export_schema(&schema_for!(MerkleProof), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
-    export_schema(&schema_for!(State), &out_dir);
+    export_schema(&schema_for!(SignedMessage), &out_dir);
+    export_schema(&schema_for!(SudoMsg), &out_dir);
+    export_schema(&schema_for!(Validator), &out_dir);
+
+    // The response to each `QueryMsg` variant.
+    let responses = [
+        ("GetDepositInfo", schema_for!(DepositInfoResponse)),
+        ("GetJobInfo", schema_for!(JobInfoResponse)),
+        ("ListJobs", schema_for!(ListJobsResponse)),
+        ("GetJobDepositors", schema_for!(JobDepositorsResponse)),
+        ("DueJobs", schema_for!(DueJobsResponse)),
+        ("GetConfig", schema_for!(Config)),
+        ("GetRelayerRewards", schema_for!(RelayerRewardsResponse)),
+        ("SimulateConsensus", schema_for!(ConsensusSimulation)),
+        ("GetSigningBytes", schema_for!(SigningBytes)),
+    ];
+    for (query, schema) in &responses {
+        export_schema_with_title(schema, &out_dir, &format!("{}Response", query));
+    }
 }
@@ -0,0 +1,86 @@
+//! Sign a consensus message and print the `ExecuteMsg::WithConsensus` that relays it.
+use secp256k1::SecretKey;
+use std::error::Error;
+use std::io::Read;
+use std::{env, fs, io, process};
+use turnstone::msg::ConsensusMsg;
+use turnstone::signer::with_consensus;
+
+const USAGE: &str = "\
+usage: turnstone-signer --keys <file> --message-id <id> [--signed-at <height>] [<msg.json>]
+
+Reads a ConsensusMsg as JSON from <msg.json>, or from stdin if omitted, signs it with
+every hex encoded secp256k1 secret key in <file>, one per line, and prints the
+ExecuteMsg::WithConsensus body to submit to the contract.";
+
+struct Args {
+    keys: String,
+    message_id: String,
+    signed_at: Option<u64>,
+    msg: Option<String>,
+}
+
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let mut keys = None;
+    let mut message_id = None;
+    let mut signed_at = None;
+    let mut msg = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--keys" => keys = Some(value()?),
+            "--message-id" => message_id = Some(value()?),
+            "--signed-at" => signed_at = Some(value()?.parse()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if msg.is_none() && !arg.starts_with('-') => msg = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg).into()),
+        }
+    }
+    Ok(Args {
+        keys: keys.ok_or("--keys is required")?,
+        message_id: message_id.ok_or("--message-id is required")?,
+        signed_at,
+        msg,
+    })
+}
+
+fn read_keys(path: &str) -> Result<Vec<SecretKey>, Box<dyn Error>> {
+    let keys = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.parse())
+        .collect::<Result<Vec<SecretKey>, _>>()?;
+    if keys.is_empty() {
+        return Err(format!("no keys in {}", path).into());
+    }
+    Ok(keys)
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = parse_args()?;
+    let keys = read_keys(&args.keys)?;
+    let msg = match &args.msg {
+        Some(path) => fs::read_to_string(path)?,
+        None => {
+            let mut msg = String::new();
+            io::stdin().read_to_string(&mut msg)?;
+            msg
+        }
+    };
+    let msg: ConsensusMsg = serde_json::from_str(&msg)?;
+    let execute_msg = with_consensus(&keys, &args.message_id, &msg, args.signed_at)?;
+    println!("{}", serde_json::to_string_pretty(&execute_msg)?);
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("error: {}\n\n{}", err, USAGE);
+        process::exit(1);
+    }
+}
@@ -1,19 +1,34 @@
-use crate::helpers::de::KeyDeserialize;
+use crate::error::{ContractError, Result};
 use crate::msg::{
-    ConsensusMsg, ExecuteMsg, InstantiateMsg, JobId, JobInfo, QueryMsg, QueryResult, Validator,
+    ConsensusMsg, DepositInfoResponse, DepositorInfo, DueJob, DueJobsResponse, ExecuteMsg,
+    InstantiateMsg, Interval, JobDepositorsResponse, JobId, JobInfo, JobInfoResponse, JobSummary,
+    ListJobsResponse, MigrateMsg, QueryMsg, RelayerRewardsResponse, Schedule, SignedMessage,
+    SigningBytes, SudoMsg, Validator,
+};
+use crate::shares::Pool;
+use crate::state::{
+    balances, Config, JobSchedule, Paused, PendingJob, RelayConfig, CONFIG, JOB_SCHEDULES,
+    JOB_TOTALS, LEGACY_BALANCES, LEGACY_BALANCES_BY_JOB_ID, NEXT_REPLY_ID, PENDING_JOBS,
+    RELAYER_REWARDS,
+};
+use crate::transaction::Transaction;
+use crate::validation::{
+    canonical_payload, hash, simulate, validate_payload, PubKey, ValKey, LEGACY_TRUSTED_ADDRESSES,
+    SIGNED_ROOTS, TRUSTED_ADDRESSES, USED_MESSAGE_IDS, VALIDATORS,
 };
-use crate::state::{BALANCES, BALANCES_BY_JOB_ID};
-use crate::validation::{validate_json, ValKey, TRUSTED_ADDRESSES, USED_MESSAGE_IDS, VALIDATORS};
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    to_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Deps, DepsMut, Env, MessageInfo, Order,
-    Response, Uint128,
+    to_json_binary, Addr, BankMsg, Binary, BlockInfo, Coin, Coins, CosmosMsg, Decimal, Deps,
+    DepsMut, Env, MessageInfo, Order, Reply, Response, StdResult, Storage, SubMsg, SubMsgResult,
+    Uint128, WasmMsg,
 };
-use cw2::set_contract_version;
-use eyre::{ensure, Result};
-use itertools::Itertools;
-use std::collections::HashMap;
+use cw2::{get_contract_version, set_contract_version};
+use cw_storage_plus::Bound;
+use semver::Version;
+
+const CONTRACT_NAME: &str = "crates.io:turnstone";
+const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
@@ -22,10 +37,20 @@ pub fn instantiate(
     info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response> {
-    const CONTRACT_NAME: &str = "crates.io:turnstone";
-    const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
+    let threshold = msg.threshold.unwrap_or_else(|| Decimal::percent(50));
+    if threshold < Decimal::percent(50) || threshold >= Decimal::one() {
+        return Err(ContractError::InvalidThreshold { threshold });
+    }
+    CONFIG.save(
+        deps.storage,
+        &Config {
+            threshold,
+            denoms: msg.denoms,
+            ..Config::default()
+        },
+    )?;
     update_valset(deps, msg.valset)?;
 
     Ok(Response::new()
@@ -34,94 +59,301 @@ pub fn instantiate(
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn execute(deps: DepsMut, _env: Env, info: MessageInfo, msg: ExecuteMsg) -> Result<Response> {
+pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> Result<Response> {
+    let config = CONFIG.load(deps.storage)?;
+    let paused = &config.paused;
+    if !matches!(
+        msg,
+        ExecuteMsg::Deposit { .. } | ExecuteMsg::DepositFor { .. }
+    ) {
+        // Funds sent with any other message would be stranded in the contract.
+        ensure_no_funds(&info)?;
+    }
     match msg {
-        ExecuteMsg::Deposit { job_id } => execute_deposit(deps, info, job_id),
-        ExecuteMsg::Withdraw { withdraw_info } => execute_withdraw(deps, info, withdraw_info),
+        ExecuteMsg::Deposit { job_id } => {
+            ensure_unpaused(paused.deposits, "deposits")?;
+            let beneficiary = info.sender.clone();
+            execute_deposit(deps, info, &config, job_id, beneficiary)
+        }
+        ExecuteMsg::DepositFor {
+            job_id,
+            beneficiary,
+        } => {
+            ensure_unpaused(paused.deposits, "deposits")?;
+            let beneficiary = deps.api.addr_validate(beneficiary.as_str())?;
+            execute_deposit(deps, info, &config, job_id, beneficiary)
+        }
+        ExecuteMsg::Withdraw { withdraw_info } => {
+            ensure_unpaused(paused.withdrawals, "withdrawals")?;
+            execute_withdraw(deps, info, withdraw_info)
+        }
+        ExecuteMsg::MoveBalance {
+            from_job,
+            to_job,
+            coin,
+        } => {
+            // A move is a withdrawal and a deposit at once.
+            ensure_unpaused(paused.withdrawals, "withdrawals")?;
+            ensure_unpaused(paused.deposits, "deposits")?;
+            execute_move_balance(deps, info, from_job, to_job, coin)
+        }
+        ExecuteMsg::TransferBalance { to, job_id, coin } => {
+            ensure_unpaused(paused.withdrawals, "withdrawals")?;
+            ensure_unpaused(paused.deposits, "deposits")?;
+            let to = deps.api.addr_validate(to.as_str())?;
+            execute_transfer_balance(deps, info, to, job_id, coin)
+        }
+        ExecuteMsg::ClaimRewards {} => {
+            ensure_unpaused(paused.withdrawals, "withdrawals")?;
+            execute_claim_rewards(deps, info)
+        }
         ExecuteMsg::WithConsensus {
             message_id,
-            raw_json,
+            payload,
+            signed_at,
+            proof,
             signatures,
         } => {
-            let consensus_msg =
-                validate_json(deps.as_ref(), &info, &message_id, &raw_json, &signatures)?;
-            // Mark this message_id as used. Messages that fail later in the process will
-            // still be able to be replayed, as this store will not be executed.
-            USED_MESSAGE_IDS.save(deps.storage, &message_id, &())?;
-            match consensus_msg {
-                ConsensusMsg::UpdateValset { valset } => update_valset(deps, valset),
-                ConsensusMsg::Stub {} => {
-                    // TODO: execute_external_contract https://github.com/palomachain/paloma/issues/109
-                    Ok(Response::new())
-                }
+            let mut validators = VALIDATORS.load(deps.storage)?;
+            execute_with_consensus(
+                deps,
+                &env,
+                &info,
+                &mut validators,
+                &SignedMessage {
+                    message_id,
+                    payload,
+                    signed_at,
+                    proof,
+                    signatures,
+                    continue_on_error: false,
+                },
+            )
+        }
+        ExecuteMsg::WithConsensusBatch { messages } => {
+            execute_with_consensus_batch(deps, env, info, messages)
+        }
+    }
+}
+
+fn ensure_no_funds(info: &MessageInfo) -> Result<()> {
+    if !info.funds.is_empty() {
+        return Err(ContractError::UnexpectedFunds {});
+    }
+    Ok(())
+}
+
+fn ensure_unpaused(paused: bool, operation: &str) -> Result<()> {
+    if paused {
+        return Err(ContractError::Paused {
+            operation: operation.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Validate a consensus message against `validators` and apply it. A valset update
+/// replaces `validators` for any messages that follow.
+fn execute_with_consensus(
+    mut deps: DepsMut,
+    env: &Env,
+    info: &MessageInfo,
+    validators: &mut Vec<ValKey>,
+    message: &SignedMessage,
+) -> Result<Response> {
+    let consensus_msg = validate_payload(deps.as_ref(), env, info, validators, message)?;
+    if let Some(proof) = &message.proof {
+        // Remember the root's signatures as verified, until the valset changes.
+        let valset_id = CONFIG.load(deps.storage)?.valset_id;
+        SIGNED_ROOTS.save(deps.storage, (valset_id, &proof.root), &())?;
+    }
+    let paused = CONFIG.load(deps.storage)?.paused;
+    let validator = TRUSTED_ADDRESSES.may_load(deps.storage, &info.sender)?;
+    let res = match consensus_msg {
+        ConsensusMsg::UpdateValset { valset } => {
+            let res = update_valset(deps.branch(), valset)?;
+            *validators = VALIDATORS.load(deps.storage)?;
+            res
+        }
+        ConsensusMsg::AddRelayer {
+            public_key,
+            address,
+        } => {
+            let address = deps.api.addr_validate(address.as_str())?;
+            add_relayer(deps.branch(), public_key, address)?
+        }
+        ConsensusMsg::RemoveRelayer {
+            public_key,
+            address,
+        } => remove_relayer(deps.branch(), public_key, address)?,
+        ConsensusMsg::SetPaused {
+            deposits,
+            withdrawals,
+            jobs,
+        } => set_paused(
+            deps.branch(),
+            Paused {
+                deposits,
+                withdrawals,
+                jobs,
+            },
+        )?,
+        ConsensusMsg::ExecuteJob {
+            job_id,
+            contract,
+            msg,
+        } => {
+            ensure_unpaused(paused.jobs, "jobs")?;
+            execute_job(
+                deps.branch(),
+                env,
+                &info.sender,
+                message,
+                job_id,
+                contract,
+                msg,
+            )?
+        }
+        ConsensusMsg::SetJobSchedule { job_id, schedule } => {
+            set_job_schedule(deps.branch(), job_id, schedule)?
+        }
+        ConsensusMsg::SetRelayerFee { fee } => set_relayer_fee(deps.branch(), fee)?,
+        ConsensusMsg::SetRelayConfig {
+            permissionless,
+            priority_blocks,
+        } => set_relay_config(
+            deps.branch(),
+            RelayConfig {
+                permissionless,
+                priority_blocks,
+            },
+        )?,
+        ConsensusMsg::Stub {} => {
+            ensure_unpaused(paused.jobs, "jobs")?;
+            // Does nothing once consensus is checked; jobs run through `ExecuteJob`.
+            Response::new()
+        }
+    };
+    // Mark this message_id as used only once it has been applied, so a message skipped
+    // in a batch may be relayed again.
+    USED_MESSAGE_IDS.save(deps.storage, &message.message_id, &())?;
+
+    let res = res
+        .add_attribute("message_id", &message.message_id)
+        .add_attribute("relayer", &info.sender);
+    Ok(match validator {
+        Some(validator) => res.add_attribute("validator", validator.0.to_base64()),
+        None => res,
+    })
+}
+
+/// Apply each message in order. Messages that fail are skipped if they allow it, and
+/// otherwise fail the whole batch. A skipped message leaves no trace in storage, and the
+/// job it ran is undone by [`reply`] if the job's target fails.
+fn execute_with_consensus_batch(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    messages: Vec<SignedMessage>,
+) -> Result<Response> {
+    if messages.is_empty() {
+        return Err(ContractError::EmptyBatch {});
+    }
+    let mut validators = VALIDATORS.load(deps.storage)?;
+    // Only jobs that failed are replied to, so these are left by the jobs that ran.
+    PENDING_JOBS.clear(deps.storage);
+    let mut res = Response::new().add_attribute("method", "with_consensus_batch");
+    for message in &messages {
+        // Stage the message's writes, to commit them only if it is applied in full.
+        let mut transaction = Transaction::new(deps.storage);
+        let mut staged_validators = validators.clone();
+        let applied = execute_with_consensus(
+            DepsMut {
+                storage: &mut transaction,
+                api: deps.api,
+                querier: deps.querier,
+            },
+            &env,
+            &info,
+            &mut staged_validators,
+            message,
+        );
+        match applied {
+            Ok(applied) => {
+                transaction.into_writes().commit(deps.storage);
+                validators = staged_validators;
+                res.messages.extend(applied.messages);
+                res.attributes.extend(applied.attributes);
+                res.events.extend(applied.events);
+            }
+            Err(err) if message.continue_on_error => {
+                res = res
+                    .add_attribute("skipped_message_id", &message.message_id)
+                    .add_attribute("error", err.to_string());
+            }
+            Err(err) => {
+                return Err(ContractError::BatchMessage {
+                    message_id: message.message_id.clone(),
+                    source: Box::new(err),
+                })
             }
         }
     }
+    Ok(res)
 }
 
-fn execute_deposit(deps: DepsMut, info: MessageInfo, job_id: JobId) -> Result<Response> {
+/// Credit the funds sent with `info` to the balance `beneficiary` holds for `job_id`.
+fn execute_deposit(
+    deps: DepsMut,
+    info: MessageInfo,
+    config: &Config,
+    job_id: JobId,
+    beneficiary: Addr,
+) -> Result<Response> {
     let MessageInfo { sender, funds } = info;
+    // Merge repeated denominations and drop zero amounts, so neither leaves a balance entry.
+    let mut deposit = Coins::default();
+    for coin in funds {
+        if let Some(denoms) = &config.denoms {
+            if !denoms.contains(&coin.denom) {
+                return Err(ContractError::DenomNotAllowed { denom: coin.denom });
+            }
+        }
+        deposit.add(coin)?;
+    }
+    if deposit.is_empty() {
+        return Err(ContractError::EmptyDeposit {});
+    }
+
     let mut res = Response::new().add_attribute("method", "deposit");
-    let mut nonzero_funds = false;
-    for coin in funds.into_iter() {
-        nonzero_funds = nonzero_funds || !coin.amount.is_zero();
-        BALANCES.update(
-            deps.storage,
-            (&sender, &job_id, &coin.denom),
-            |balance| -> Result<Uint128> {
-                Ok(match balance {
-                    Some(balance) => balance + coin.amount,
-                    None => coin.amount,
-                })
-            },
-        )?;
-        BALANCES_BY_JOB_ID.save(deps.storage, (&job_id, &sender, &coin.denom), &())?;
+    if beneficiary != sender {
+        res = res
+            .add_attribute("sender", sender)
+            .add_attribute("beneficiary", &beneficiary);
+    }
+    for coin in deposit {
+        credit(deps.storage, &beneficiary, &job_id, &coin)?;
         res = res
             .add_attribute("job_id", &job_id.0)
             .add_attribute("denom", &coin.denom)
             .add_attribute("amount", coin.amount);
     }
-    ensure!(nonzero_funds, "attempting to deposit 0 funds");
-
     Ok(res)
 }
 
 fn execute_withdraw(deps: DepsMut, info: MessageInfo, withdraws: Vec<JobInfo>) -> Result<Response> {
-    ensure!(!withdraws.is_empty(), "must execute some withdrawal");
+    if withdraws.is_empty() {
+        return Err(ContractError::EmptyWithdrawal {});
+    }
     let mut res = Response::new().add_attribute("method", "withdraw");
     let mut coins = Vec::with_capacity(withdraws.len());
     for withdraw in withdraws {
-        let amount = BALANCES
-            .may_load(
-                deps.storage,
-                (&info.sender, &withdraw.job_id, &withdraw.coin.denom),
-            )?
-            .unwrap_or_default();
-        let amount = amount.checked_sub(withdraw.coin.amount)?;
-        if amount.is_zero() {
-            BALANCES.remove(
-                deps.storage,
-                (&info.sender, &withdraw.job_id, &withdraw.coin.denom),
-            );
-            BALANCES_BY_JOB_ID.remove(
-                deps.storage,
-                (&withdraw.job_id, &info.sender, &withdraw.coin.denom),
-            );
-        } else {
-            BALANCES.save(
-                deps.storage,
-                (&info.sender, &withdraw.job_id, &withdraw.coin.denom),
-                &amount,
-            )?;
-        }
+        debit(deps.storage, &info.sender, &withdraw.job_id, &withdraw.coin)?;
         res = res
             .add_attribute("job_id", withdraw.job_id.0)
             .add_attribute("denom", &withdraw.coin.denom)
-            .add_attribute("amount", amount);
-        coins.push(Coin {
-            amount,
-            denom: withdraw.coin.denom.clone(),
-        });
+            .add_attribute("amount", withdraw.coin.amount);
+        coins.push(withdraw.coin);
     }
     Ok(res.add_message(CosmosMsg::Bank(BankMsg::Send {
         to_address: info.sender.to_string(),
@@ -129,67 +361,808 @@ fn execute_withdraw(deps: DepsMut, info: MessageInfo, withdraws: Vec<JobInfo>) -
     })))
 }
 
+fn execute_move_balance(
+    deps: DepsMut,
+    info: MessageInfo,
+    from_job: JobId,
+    to_job: JobId,
+    coin: Coin,
+) -> Result<Response> {
+    if coin.amount.is_zero() {
+        return Err(ContractError::EmptyTransfer {});
+    }
+    debit(deps.storage, &info.sender, &from_job, &coin)?;
+    credit(deps.storage, &info.sender, &to_job, &coin)?;
+    Ok(Response::new()
+        .add_attribute("method", "move_balance")
+        .add_attribute("from_job", from_job.0)
+        .add_attribute("to_job", to_job.0)
+        .add_attribute("denom", coin.denom)
+        .add_attribute("amount", coin.amount))
+}
+
+fn execute_transfer_balance(
+    deps: DepsMut,
+    info: MessageInfo,
+    to: Addr,
+    job_id: JobId,
+    coin: Coin,
+) -> Result<Response> {
+    if coin.amount.is_zero() {
+        return Err(ContractError::EmptyTransfer {});
+    }
+    debit(deps.storage, &info.sender, &job_id, &coin)?;
+    credit(deps.storage, &to, &job_id, &coin)?;
+    Ok(Response::new()
+        .add_attribute("method", "transfer_balance")
+        .add_attribute("job_id", job_id.0)
+        .add_attribute("to", to)
+        .add_attribute("denom", coin.denom)
+        .add_attribute("amount", coin.amount))
+}
+
+fn execute_claim_rewards(deps: DepsMut, info: MessageInfo) -> Result<Response> {
+    let rewards = query_relayer_rewards(deps.as_ref(), &info.sender)?;
+    if rewards.is_empty() {
+        return Err(ContractError::NoRewards {});
+    }
+    for coin in &rewards {
+        RELAYER_REWARDS.remove(deps.storage, (&info.sender, &coin.denom));
+    }
+    Ok(Response::new()
+        .add_attribute("method", "claim_rewards")
+        .add_message(CosmosMsg::Bank(BankMsg::Send {
+            to_address: info.sender.to_string(),
+            amount: rewards,
+        })))
+}
+
+/// Add `coin` to the balance `address` holds for `job_id`.
+fn credit(storage: &mut dyn Storage, address: &Addr, job_id: &JobId, coin: &Coin) -> Result<()> {
+    let balances = balances();
+    let key = (address, job_id, coin.denom.as_str());
+    let mut pool = Pool::load(storage, job_id, &coin.denom)?;
+    let deposit = pool.deposit(balances.may_load(storage, key)?.as_ref(), coin.amount)?;
+    balances.save(storage, key, &deposit)?;
+    pool.save(storage, job_id, &coin.denom)?;
+    Ok(())
+}
+
+/// Remove `coin` from the balance `address` holds for `job_id`, failing if it is too small.
+fn debit(storage: &mut dyn Storage, address: &Addr, job_id: &JobId, coin: &Coin) -> Result<()> {
+    let balances = balances();
+    let key = (address, job_id, coin.denom.as_str());
+    let mut pool = Pool::load(storage, job_id, &coin.denom)?;
+    let deposit = balances.may_load(storage, key)?.unwrap_or_default();
+    let available = pool.balance(&deposit);
+    if available < coin.amount {
+        return Err(insufficient_balance(job_id, coin, available));
+    }
+    match pool.withdraw(&deposit, coin.amount)? {
+        Some(deposit) => balances.save(storage, key, &deposit)?,
+        None => balances.remove(storage, key)?,
+    }
+    pool.save(storage, job_id, &coin.denom)?;
+    Ok(())
+}
+
+/// Charge `coin` to the funds of `job_id`, and so to its depositors pro rata to their
+/// balances of its denomination, without touching any of them.
+fn charge_job(storage: &mut dyn Storage, job_id: &JobId, coin: &Coin) -> Result<()> {
+    let mut pool = Pool::load(storage, job_id, &coin.denom)?;
+    if pool.funds < coin.amount {
+        return Err(insufficient_balance(job_id, coin, pool.funds));
+    }
+    pool.charge(coin.amount)?;
+    pool.save(storage, job_id, &coin.denom)?;
+    Ok(())
+}
+
+/// Return `coin` charged to the funds of `job_id`.
+fn refund_job(storage: &mut dyn Storage, job_id: &JobId, coin: &Coin) -> Result<()> {
+    let mut pool = Pool::load(storage, job_id, &coin.denom)?;
+    pool.refund(coin.amount)?;
+    pool.save(storage, job_id, &coin.denom)?;
+    Ok(())
+}
+
+fn insufficient_balance(job_id: &JobId, coin: &Coin, available: Uint128) -> ContractError {
+    ContractError::InsufficientBalance {
+        job_id: job_id.clone(),
+        denom: coin.denom.clone(),
+        available,
+        requested: coin.amount,
+    }
+}
+
 fn update_valset(deps: DepsMut, valset: Vec<Validator>) -> Result<Response> {
-    let (mut validators, addresses): (Vec<_>, Vec<_>) = valset
-        .into_iter()
-        .map(|val| {
-            (
-                ValKey {
-                    pubkey: val.public_key,
-                    stake: val.stake,
-                },
-                val.address,
-            )
-        })
-        .unzip();
-    let mut addresses = addresses.into_iter().concat();
-    addresses.sort();
+    let old_addresses = TRUSTED_ADDRESSES
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for address in &old_addresses {
+        TRUSTED_ADDRESSES.remove(deps.storage, address);
+    }
+    let mut validators = Vec::with_capacity(valset.len());
+    for val in valset {
+        for address in &val.address {
+            // Each address relays for a single validator.
+            if TRUSTED_ADDRESSES.has(deps.storage, address) {
+                return Err(ContractError::DuplicateRelayer {
+                    address: address.clone(),
+                });
+            }
+            TRUSTED_ADDRESSES.save(deps.storage, address, &val.public_key)?;
+        }
+        validators.push(ValKey {
+            pubkey: val.public_key,
+            stake: val.stake,
+        });
+    }
     validators.sort_by(|v1, v2| v1.pubkey.cmp(&v2.pubkey));
-    TRUSTED_ADDRESSES.save(deps.storage, &addresses)?;
     VALIDATORS.save(deps.storage, &validators)?;
+    // Moving to a new valset id retires the roots verified against the outgoing valset.
+    CONFIG.update(deps.storage, |mut config| -> StdResult<Config> {
+        config.valset_id += 1;
+        Ok(config)
+    })?;
     Ok(Response::new().add_attribute("method", "update_valset"))
 }
 
+fn add_relayer(deps: DepsMut, public_key: PubKey, address: Addr) -> Result<Response> {
+    let validators = VALIDATORS.load(deps.storage)?;
+    if validators
+        .binary_search_by(|probe| probe.pubkey.cmp(&public_key))
+        .is_err()
+    {
+        return Err(ContractError::UnknownValidator { public_key });
+    }
+    if let Some(current) = TRUSTED_ADDRESSES.may_load(deps.storage, &address)? {
+        if current != public_key {
+            return Err(ContractError::RelayerConflict {
+                address,
+                validator: current,
+            });
+        }
+    }
+    TRUSTED_ADDRESSES.save(deps.storage, &address, &public_key)?;
+    Ok(Response::new()
+        .add_attribute("method", "add_relayer")
+        .add_attribute("validator", public_key.0.to_base64())
+        .add_attribute("address", address))
+}
+
+fn remove_relayer(deps: DepsMut, public_key: PubKey, address: Addr) -> Result<Response> {
+    if TRUSTED_ADDRESSES.may_load(deps.storage, &address)? != Some(public_key.clone()) {
+        return Err(ContractError::NotRelayerOf {
+            address,
+            public_key,
+        });
+    }
+    TRUSTED_ADDRESSES.remove(deps.storage, &address);
+    Ok(Response::new()
+        .add_attribute("method", "remove_relayer")
+        .add_attribute("validator", public_key.0.to_base64())
+        .add_attribute("address", address))
+}
+
+/// Run a job on its target contract, charging the relayer fee to the job's deposits.
+/// The job of a message that may be skipped is dispatched to be replied to on failure.
+fn execute_job(
+    deps: DepsMut,
+    env: &Env,
+    relayer: &Addr,
+    message: &SignedMessage,
+    job_id: JobId,
+    contract: Addr,
+    msg: Binary,
+) -> Result<Response> {
+    let mut res = Response::new()
+        .add_attribute("method", "execute_job")
+        .add_attribute("job_id", &job_id.0)
+        .add_attribute("contract", &contract);
+    let mut pending = PendingJob {
+        message_id: message.message_id.clone(),
+        job_id: job_id.clone(),
+        relayer: relayer.clone(),
+        window: None,
+        previous_window: None,
+        fee: None,
+    };
+    let schedule = JOB_SCHEDULES.may_load(deps.storage, &job_id)?;
+    if let Some(schedule) = &schedule {
+        let window = due_window(&job_id, schedule, &env.block)?;
+        pending.window = Some(window);
+        pending.previous_window = schedule.last_window;
+        res = res.add_attribute("window", window.to_string());
+    }
+    if let Some(fee) = CONFIG.load(deps.storage)?.relayer_fee {
+        charge_job(deps.storage, &job_id, &fee)?;
+        RELAYER_REWARDS.update(
+            deps.storage,
+            (relayer, &fee.denom),
+            |reward| -> Result<Uint128> { Ok(reward.unwrap_or_default() + fee.amount) },
+        )?;
+        res = res.add_attribute("relayer_fee", fee.to_string());
+        pending.fee = Some(fee);
+    }
+    // The window is only used up once the job has paid for it.
+    if let (Some(mut schedule), Some(window)) = (schedule, pending.window) {
+        schedule.last_window = Some(window);
+        JOB_SCHEDULES.save(deps.storage, &job_id, &schedule)?;
+    }
+    let msg = WasmMsg::Execute {
+        contract_addr: contract.into_string(),
+        msg,
+        funds: vec![],
+    };
+    if !message.continue_on_error {
+        return Ok(res.add_message(msg));
+    }
+    let id = NEXT_REPLY_ID.may_load(deps.storage)?.unwrap_or_default();
+    NEXT_REPLY_ID.save(deps.storage, &(id + 1))?;
+    PENDING_JOBS.save(deps.storage, id, &pending)?;
+    Ok(res.add_submessage(SubMsg::reply_on_error(msg, id)))
+}
+
+/// The start of the window `block` falls in, if the job may run in it.
+fn due_window(job_id: &JobId, schedule: &JobSchedule, block: &BlockInfo) -> Result<u64> {
+    let Schedule {
+        interval,
+        start,
+        end,
+    } = schedule.schedule;
+    let (now, length) = match interval {
+        Interval::Blocks(blocks) => (block.height, blocks),
+        Interval::Seconds(seconds) => (block.time.seconds(), seconds),
+    };
+    let ended = |at: u64| end.is_some_and(|end| at >= end);
+    if ended(now) {
+        return Err(ContractError::ScheduleEnded {
+            job_id: job_id.clone(),
+        });
+    }
+    if now < start {
+        return Err(ContractError::JobNotDue {
+            job_id: job_id.clone(),
+            next_window: start,
+        });
+    }
+    let window = now - (now - start) % length;
+    if schedule.last_window == Some(window) {
+        let next_window = window.saturating_add(length);
+        return Err(if ended(next_window) {
+            ContractError::ScheduleEnded {
+                job_id: job_id.clone(),
+            }
+        } else {
+            ContractError::JobNotDue {
+                job_id: job_id.clone(),
+                next_window,
+            }
+        });
+    }
+    Ok(window)
+}
+
+fn set_job_schedule(deps: DepsMut, job_id: JobId, schedule: Option<Schedule>) -> Result<Response> {
+    let res = Response::new()
+        .add_attribute("method", "set_job_schedule")
+        .add_attribute("job_id", &job_id.0);
+    let schedule = match schedule {
+        Some(schedule) => schedule,
+        None => {
+            JOB_SCHEDULES.remove(deps.storage, &job_id);
+            return Ok(res.add_attribute("schedule", "none"));
+        }
+    };
+    let (Interval::Blocks(length) | Interval::Seconds(length)) = schedule.interval;
+    if length == 0 || schedule.end.is_some_and(|end| end <= schedule.start) {
+        return Err(ContractError::InvalidSchedule {});
+    }
+    JOB_SCHEDULES.save(
+        deps.storage,
+        &job_id,
+        &JobSchedule {
+            schedule,
+            last_window: None,
+        },
+    )?;
+    Ok(res)
+}
+
+fn set_relayer_fee(deps: DepsMut, fee: Option<Coin>) -> Result<Response> {
+    CONFIG.update(deps.storage, |mut config| -> StdResult<Config> {
+        config.relayer_fee = fee.clone();
+        Ok(config)
+    })?;
+    Ok(Response::new()
+        .add_attribute("method", "set_relayer_fee")
+        .add_attribute(
+            "fee",
+            fee.map_or_else(|| "none".to_string(), |fee| fee.to_string()),
+        ))
+}
+
+fn set_relay_config(deps: DepsMut, relay: RelayConfig) -> Result<Response> {
+    CONFIG.update(deps.storage, |mut config| -> StdResult<Config> {
+        config.relay = relay.clone();
+        Ok(config)
+    })?;
+    Ok(Response::new()
+        .add_attribute("method", "set_relay_config")
+        .add_attribute("permissionless", relay.permissionless.to_string())
+        .add_attribute("priority_blocks", relay.priority_blocks.to_string()))
+}
+
+fn set_paused(deps: DepsMut, paused: Paused) -> Result<Response> {
+    CONFIG.update(deps.storage, |mut config| -> StdResult<Config> {
+        config.paused = paused.clone();
+        Ok(config)
+    })?;
+    Ok(Response::new()
+        .add_attribute("method", "set_paused")
+        .add_attribute("deposits", paused.deposits.to_string())
+        .add_attribute("withdrawals", paused.withdrawals.to_string())
+        .add_attribute("jobs", paused.jobs.to_string()))
+}
+
+/// Undo the job of a skippable message whose target failed, skipping the message as if it
+/// had failed in the batch.
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, _env: Env, reply: Reply) -> Result<Response> {
+    let error = match reply.result {
+        SubMsgResult::Err(error) => error,
+        // Jobs are only dispatched to be replied to on failure.
+        SubMsgResult::Ok(_) => return Ok(Response::new()),
+    };
+    // A job whose entry is gone can't be undone, and fails the transaction instead.
+    let pending = PENDING_JOBS
+        .may_load(deps.storage, reply.id)?
+        .ok_or(ContractError::UnknownReply { id: reply.id })?;
+    PENDING_JOBS.remove(deps.storage, reply.id);
+    USED_MESSAGE_IDS.remove(deps.storage, &pending.message_id);
+    if let Some(window) = pending.window {
+        if let Some(mut schedule) = JOB_SCHEDULES.may_load(deps.storage, &pending.job_id)? {
+            // Unless a later message has since replaced the schedule.
+            if schedule.last_window == Some(window) {
+                schedule.last_window = pending.previous_window;
+                JOB_SCHEDULES.save(deps.storage, &pending.job_id, &schedule)?;
+            }
+        }
+    }
+    if let Some(fee) = &pending.fee {
+        refund_job(deps.storage, &pending.job_id, fee)?;
+        let key = (&pending.relayer, fee.denom.as_str());
+        let reward = RELAYER_REWARDS
+            .load(deps.storage, key)?
+            .checked_sub(fee.amount)?;
+        if reward.is_zero() {
+            RELAYER_REWARDS.remove(deps.storage, key);
+        } else {
+            RELAYER_REWARDS.save(deps.storage, key, &reward)?;
+        }
+    }
+    Ok(Response::new()
+        .add_attribute("method", "reply")
+        .add_attribute("skipped_message_id", pending.message_id)
+        .add_attribute("error", error))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn sudo(deps: DepsMut, _env: Env, msg: SudoMsg) -> Result<Response> {
+    let res = match msg {
+        SudoMsg::UpdateValset { valset } => update_valset(deps, valset)?,
+        SudoMsg::SetPaused {
+            deposits,
+            withdrawals,
+            jobs,
+        } => set_paused(
+            deps,
+            Paused {
+                deposits,
+                withdrawals,
+                jobs,
+            },
+        )?,
+    };
+    Ok(res.add_attribute("sudo", "true"))
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> Result<Binary> {
-    Ok(to_binary(&match msg {
-        QueryMsg::GetDepositInfo { address } => {
-            QueryResult::Jobs(query_address_info(deps, &address)?)
+pub fn migrate(mut deps: DepsMut, _env: Env, msg: MigrateMsg) -> Result<Response> {
+    // Only ever upgrade this contract.
+    let previous = get_contract_version(deps.storage)?;
+    if previous.contract != CONTRACT_NAME {
+        return Err(ContractError::WrongContract {
+            contract: previous.contract,
+        });
+    }
+    if parse_version(&previous.version)? > parse_version(CONTRACT_VERSION)? {
+        return Err(ContractError::Downgrade {
+            from: previous.version,
+            to: CONTRACT_VERSION.to_string(),
+        });
+    }
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    // The earliest versions kept no configuration.
+    if !CONFIG.exists(deps.storage) {
+        CONFIG.save(deps.storage, &Config::default())?;
+    }
+
+    // The earliest versions kept trusted addresses in a bare list, without the validator
+    // each relays for.
+    if let Some(addresses) = LEGACY_TRUSTED_ADDRESSES.may_load(deps.storage)? {
+        let validators = VALIDATORS.load(deps.storage)?;
+        for address in addresses {
+            let relayer = msg
+                .relayers
+                .iter()
+                .find(|relayer| relayer.address == address);
+            let public_key = match (relayer, validators.as_slice()) {
+                (Some(relayer), _) => relayer.public_key.clone(),
+                (None, [only]) => only.pubkey.clone(),
+                (None, _) => return Err(ContractError::UnmappedRelayer { address }),
+            };
+            add_relayer(deps.branch(), public_key, address)?;
+        }
+        LEGACY_TRUSTED_ADDRESSES.remove(deps.storage);
+    }
+
+    let mut res = Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("previous_version", &previous.version)
+        .add_attribute("version", CONTRACT_VERSION);
+
+    // Versions before 0.2.0 kept deposits by amount, with a hand-maintained reverse
+    // index, and no running totals. Some also recorded the zero coins in a deposit, as
+    // zero balances.
+    if parse_version(&previous.version)? < Version::new(0, 2, 0) {
+        // Deposit every balance afresh, issuing its shares and indexing it by job.
+        let entries = LEGACY_BALANCES
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut indexed = 0;
+        let mut zero_balances = 0;
+        for ((address, job_id, denom), amount) in &entries {
+            LEGACY_BALANCES.remove(deps.storage, (address, job_id, denom));
+            if amount.is_zero() {
+                zero_balances += 1;
+            } else {
+                credit(
+                    deps.storage,
+                    address,
+                    job_id,
+                    &Coin::new(amount.u128(), denom),
+                )?;
+                indexed += 1;
+            }
         }
-        QueryMsg::GetJobInfo { job_id } => QueryResult::Balance(query_job_info(deps, &job_id)?),
-    })?)
+        LEGACY_BALANCES_BY_JOB_ID.clear(deps.storage);
+        let totals = JOB_TOTALS
+            .keys_raw(deps.storage, None, None, Order::Ascending)
+            .count();
+        res = res
+            .add_attribute("balances", indexed.to_string())
+            .add_attribute("job_totals", totals.to_string())
+            .add_attribute("zero_balances_removed", zero_balances.to_string());
+    }
+    Ok(res)
 }
 
-/// Fetch the coins associated to every job under the given address.
-fn query_address_info(deps: Deps, address: &Addr) -> Result<Vec<JobInfo>> {
-    BALANCES
-        .sub_prefix(address)
+fn parse_version(version: &str) -> Result<Version> {
+    Version::parse(version).map_err(|err| ContractError::InvalidVersion {
+        version: version.to_string(),
+        reason: err.to_string(),
+    })
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary> {
+    Ok(match msg {
+        QueryMsg::GetDepositInfo {
+            address,
+            start_after,
+            limit,
+        } => to_json_binary(&query_address_info(deps, address, start_after, limit)?),
+        QueryMsg::GetJobInfo { job_id } => to_json_binary(&JobInfoResponse {
+            funds: query_job_info(deps, &job_id)?,
+            job_id,
+        }),
+        QueryMsg::DueJobs { start_after, limit } => {
+            to_json_binary(&query_due_jobs(deps, &env, start_after, limit)?)
+        }
+        QueryMsg::GetConfig {} => to_json_binary(&CONFIG.load(deps.storage)?),
+        QueryMsg::GetRelayerRewards { address } => to_json_binary(&RelayerRewardsResponse {
+            rewards: query_relayer_rewards(deps, &address)?,
+            address,
+        }),
+        QueryMsg::GetSigningBytes {
+            message_id,
+            msg,
+            signed_at,
+        } => to_json_binary(&query_signing_bytes(&message_id, &msg, signed_at)?),
+        QueryMsg::SimulateConsensus {
+            message_id,
+            payload,
+            signed_at,
+            proof,
+            signatures,
+            relayer,
+        } => to_json_binary(&simulate(
+            deps,
+            &env,
+            relayer.as_ref(),
+            &SignedMessage {
+                message_id,
+                payload,
+                signed_at,
+                proof,
+                signatures,
+                continue_on_error: false,
+            },
+        )?),
+        QueryMsg::ListJobs { start_after, limit } => {
+            to_json_binary(&query_list_jobs(deps, start_after, limit)?)
+        }
+        QueryMsg::GetJobDepositors {
+            job_id,
+            start_after,
+            limit,
+        } => to_json_binary(&query_job_depositors(deps, job_id, start_after, limit)?),
+    }?)
+}
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
+/// The page size for a listing: `DEFAULT_LIMIT` when none is given, capped at
+/// `MAX_LIMIT`, and at least one so a page always makes progress.
+fn page_limit(limit: Option<u32>) -> usize {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT) as usize
+}
+
+/// The first raw key past every key beginning with `component`, a leading component of a
+/// composite key, stored after its length. It makes an inclusive [`Bound`] that resumes a
+/// range after the last such key, without reading any of them.
+fn key_after(component: &str) -> Vec<u8> {
+    let length = (component.len() as u16).to_be_bytes();
+    let mut key = [&length[..], component.as_bytes()].concat();
+    // The last byte is either the length of an empty component or UTF-8, which never
+    // holds 0xff, so this cannot overflow.
+    *key.last_mut().expect("the length is always stored") += 1;
+    key
+}
+
+/// Page through the coins `address` holds for each job, with one entry per denomination.
+fn query_address_info(
+    deps: Deps,
+    address: Addr,
+    start_after: Option<JobId>,
+    limit: Option<u32>,
+) -> Result<DepositInfoResponse> {
+    let limit = page_limit(limit);
+    let start = start_after
+        .as_ref()
+        .map(|job_id| Bound::InclusiveRaw(key_after(&job_id.0)));
+    let mut deposits: Vec<JobInfo> = Vec::new();
+    let mut count = 0;
+    let mut next_cursor = None;
+    for item in balances()
+        .sub_prefix(&address)
+        .range(deps.storage, start, None, Order::Ascending)
+    {
+        let ((job_id, denom), deposit) = item?;
+        let amount = Pool::load(deps.storage, &job_id, &denom)?.balance(&deposit);
+        if amount.is_zero() {
+            // Fees have taken all of it.
+            continue;
+        }
+        if deposits.last().map(|last| &last.job_id) != Some(&job_id) {
+            if count == limit {
+                next_cursor = deposits.last().map(|last| last.job_id.clone());
+                break;
+            }
+            count += 1;
+        }
+        deposits.push(JobInfo {
+            coin: Coin { denom, amount },
+            job_id,
+        });
+    }
+    Ok(DepositInfoResponse {
+        address,
+        deposits,
+        next_cursor,
+    })
+}
+
+/// Fetch the funds associated with a given `JobId`, summed by denomination.
+fn query_job_info(deps: Deps, job_id: &JobId) -> Result<Vec<Coin>> {
+    JOB_TOTALS
+        .prefix(job_id)
         .range(deps.storage, None, None, Order::Ascending)
         .map(|item| {
-            let (key, amount) = item?;
-            let (job_id, denom) = <(JobId, String)>::from_slice(&key)?;
-            Ok(JobInfo {
-                coin: Coin { denom, amount },
-                job_id,
-            })
+            let (denom, amount) = item?;
+            Ok(Coin { denom, amount })
         })
         .collect()
 }
 
-/// Fetch the funds associated with a given `JobId`, summed by denomination.
-fn query_job_info(deps: Deps, job_id: &JobId) -> Result<Vec<Coin>> {
-    let mut balance: HashMap<String, Uint128> = HashMap::new();
-    for key in
-        BALANCES_BY_JOB_ID
-            .sub_prefix(job_id)
-            .keys(deps.storage, None, None, Order::Ascending)
+/// Fetch the fees `address` has earned relaying jobs and not yet claimed.
+fn query_relayer_rewards(deps: Deps, address: &Addr) -> Result<Vec<Coin>> {
+    RELAYER_REWARDS
+        .prefix(address)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (denom, amount) = item?;
+            Ok(Coin { denom, amount })
+        })
+        .collect()
+}
+
+fn query_signing_bytes(
+    message_id: &str,
+    msg: &ConsensusMsg,
+    signed_at: Option<u64>,
+) -> Result<SigningBytes> {
+    let payload = canonical_payload(msg)?;
+    Ok(SigningBytes {
+        hash: Binary::from(hash(message_id, &payload, signed_at)),
+        payload: Binary::from(payload),
+    })
+}
+
+/// Page through every job with a deposit, summarizing its funds and depositors.
+fn query_list_jobs(
+    deps: Deps,
+    start_after: Option<JobId>,
+    limit: Option<u32>,
+) -> Result<ListJobsResponse> {
+    let limit = page_limit(limit);
+    let start = start_after
+        .as_ref()
+        .map(|job_id| Bound::InclusiveRaw(key_after(&job_id.0)));
+    // The index is ordered by the stored `job_id` and then by address, so each job's
+    // depositors are contiguous and each depositor's denominations follow one another.
+    let mut jobs: Vec<(JobId, u32)> = Vec::new();
+    let mut last_address: Option<Addr> = None;
+    let mut next_cursor = None;
+    for item in balances()
+        .idx
+        .job_id
+        .range(deps.storage, start, None, Order::Ascending)
     {
-        let (address, denom) = <(Addr, String)>::from_vec(key)?;
-        let amount = BALANCES.load(deps.storage, (&address, job_id, &denom))?;
-        *balance.entry(denom).or_default() += amount;
+        let ((address, job_id, denom), deposit) = item?;
+        if Pool::load(deps.storage, &job_id, &denom)?
+            .balance(&deposit)
+            .is_zero()
+        {
+            continue;
+        }
+        match jobs.last_mut() {
+            Some((last_job_id, depositors)) if *last_job_id == job_id => {
+                if last_address.as_ref() != Some(&address) {
+                    *depositors += 1;
+                }
+            }
+            _ => {
+                if jobs.len() == limit {
+                    next_cursor = jobs.last().map(|(job_id, _)| job_id.clone());
+                    break;
+                }
+                jobs.push((job_id, 1));
+            }
+        }
+        last_address = Some(address);
+    }
+    let jobs = jobs
+        .into_iter()
+        .map(|(job_id, depositors)| {
+            Ok(JobSummary {
+                funds: query_job_info(deps, &job_id)?,
+                job_id,
+                depositors,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(ListJobsResponse { jobs, next_cursor })
+}
+
+/// Page through the scheduled jobs that may run in the current block.
+fn query_due_jobs(
+    deps: Deps,
+    env: &Env,
+    start_after: Option<JobId>,
+    limit: Option<u32>,
+) -> Result<DueJobsResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.paused.jobs {
+        return Ok(DueJobsResponse {
+            jobs: vec![],
+            next_cursor: None,
+        });
     }
-    Ok(balance
+    let limit = page_limit(limit);
+    let start = start_after.as_ref().map(Bound::exclusive);
+    // Scan at most `limit` schedules, however few of them are due, and one more to
+    // learn whether there are others.
+    let mut schedules = JOB_SCHEDULES
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit + 1)
+        .collect::<StdResult<Vec<_>>>()?;
+    let next_cursor = if schedules.len() > limit {
+        schedules.truncate(limit);
+        schedules.last().map(|(job_id, _)| job_id.clone())
+    } else {
+        None
+    };
+    let jobs = schedules
         .into_iter()
-        .map(|(denom, amount)| Coin { amount, denom })
-        .collect())
+        .map(|(job_id, schedule)| {
+            let Ok(window) = due_window(&job_id, &schedule, &env.block) else {
+                return Ok(None);
+            };
+            // A job that can't pay its relayer would only be skipped.
+            if let Some(fee) = &config.relayer_fee {
+                let funds = JOB_TOTALS
+                    .may_load(deps.storage, (&job_id, &fee.denom))?
+                    .unwrap_or_default();
+                if funds < fee.amount {
+                    return Ok(None);
+                }
+            }
+            Ok(Some(DueJob {
+                job_id,
+                schedule: schedule.schedule,
+                window,
+            }))
+        })
+        .filter_map(Result::transpose)
+        .collect::<StdResult<_>>()?;
+    Ok(DueJobsResponse { jobs, next_cursor })
+}
+
+/// Page through the depositors of a given `JobId`, with one entry per denomination.
+fn query_job_depositors(
+    deps: Deps,
+    job_id: JobId,
+    start_after: Option<Addr>,
+    limit: Option<u32>,
+) -> Result<JobDepositorsResponse> {
+    let limit = page_limit(limit);
+    let start = start_after
+        .as_ref()
+        .map(|address| Bound::InclusiveRaw(key_after(address.as_str())));
+    let mut depositors: Vec<DepositorInfo> = Vec::new();
+    let mut count = 0;
+    let mut next_cursor = None;
+    for item in balances().idx.job_id.prefix(job_id.clone()).range(
+        deps.storage,
+        start,
+        None,
+        Order::Ascending,
+    ) {
+        let ((address, _, denom), deposit) = item?;
+        let amount = Pool::load(deps.storage, &job_id, &denom)?.balance(&deposit);
+        if amount.is_zero() {
+            continue;
+        }
+        if depositors.last().map(|last| &last.address) != Some(&address) {
+            if count == limit {
+                next_cursor = depositors.last().map(|last| last.address.clone());
+                break;
+            }
+            count += 1;
+        }
+        depositors.push(DepositorInfo {
+            address,
+            coin: Coin { denom, amount },
+        });
+    }
+    Ok(JobDepositorsResponse {
+        job_id,
+        depositors,
+        next_cursor,
+    })
 }
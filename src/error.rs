@@ -0,0 +1,139 @@
+use crate::msg::JobId;
+use crate::validation::PubKey;
+use cosmwasm_std::{Addr, Decimal, OverflowError, StdError, Uint128, VerificationError};
+use schemars::JsonSchema;
+use thiserror::Error;
+
+pub type Result<T, E = ContractError> = std::result::Result<T, E>;
+
+/// Every way the contract can fail. Messages reach clients through `Display`; the
+/// variants and their data are exported in the schema.
+#[derive(Error, Debug, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ContractError {
+    #[error("{0}")]
+    Std(
+        #[from]
+        #[schemars(with = "String")]
+        StdError,
+    ),
+
+    #[error("{0}")]
+    Overflow(
+        #[from]
+        #[schemars(with = "String")]
+        OverflowError,
+    ),
+
+    #[error("{0}")]
+    Verification(
+        #[from]
+        #[schemars(with = "String")]
+        VerificationError,
+    ),
+
+    #[error("invalid payload: {0}")]
+    InvalidPayload(
+        #[from]
+        #[schemars(with = "String")]
+        serde_json::Error,
+    ),
+
+    #[error("payload is not canonically encoded")]
+    NonCanonicalPayload {},
+
+    #[error("{operation} are paused")]
+    Paused { operation: String },
+
+    #[error("threshold {threshold} is not at least one half and less than one")]
+    InvalidThreshold { threshold: Decimal },
+
+    #[error("deposits of {denom} are not accepted")]
+    DenomNotAllowed { denom: String },
+
+    #[error("attempting to deposit 0 funds")]
+    EmptyDeposit {},
+
+    #[error("must execute some withdrawal")]
+    EmptyWithdrawal {},
+
+    #[error("must move a nonzero amount")]
+    EmptyTransfer {},
+
+    #[error("funds may only be sent with a deposit")]
+    UnexpectedFunds {},
+
+    #[error("insufficient balance for job {}: {available}{denom} available, {requested}{denom} requested", job_id.0)]
+    InsufficientBalance {
+        job_id: JobId,
+        denom: String,
+        available: Uint128,
+        requested: Uint128,
+    },
+
+    #[error("no rewards to claim")]
+    NoRewards {},
+
+    #[error("previously used message_id {message_id}")]
+    MessageIdUsed { message_id: String },
+
+    #[error("{relayer} is not a trusted relayer")]
+    UntrustedRelayer { relayer: Addr },
+
+    #[error("untrusted relayers must submit the signing height")]
+    MissingSigningHeight {},
+
+    #[error("message is reserved for trusted relayers until height {until}")]
+    PriorityWindow { until: u64 },
+
+    #[error("signatures carry {signed} stake, but {required} is required")]
+    ThresholdNotMet { signed: Uint128, required: Uint128 },
+
+    #[error("schedule intervals must be nonzero, and end after they start")]
+    InvalidSchedule {},
+
+    #[error("job {} is not due until {next_window}", job_id.0)]
+    JobNotDue { job_id: JobId, next_window: u64 },
+
+    #[error("the schedule of job {} has ended", job_id.0)]
+    ScheduleEnded { job_id: JobId },
+
+    #[error("invalid merkle proof")]
+    InvalidMerkleProof {},
+
+    #[error("{} is not in the valset", public_key.0)]
+    UnknownValidator { public_key: PubKey },
+
+    #[error("{address} relays for another validator, {}", validator.0)]
+    RelayerConflict { address: Addr, validator: PubKey },
+
+    #[error("{address} is listed more than once in the valset")]
+    DuplicateRelayer { address: Addr },
+
+    #[error("no validator given for trusted address {address}")]
+    UnmappedRelayer { address: Addr },
+
+    #[error("{address} does not relay for {}", public_key.0)]
+    NotRelayerOf { address: Addr, public_key: PubKey },
+
+    #[error("no pending job for reply {id}")]
+    UnknownReply { id: u64 },
+
+    #[error("cannot migrate from contract {contract}")]
+    WrongContract { contract: String },
+
+    #[error("cannot migrate from version {from} down to {to}")]
+    Downgrade { from: String, to: String },
+
+    #[error("invalid contract version {version}: {reason}")]
+    InvalidVersion { version: String, reason: String },
+
+    #[error("must submit some messages")]
+    EmptyBatch {},
+
+    #[error("message_id {message_id}: {source}")]
+    BatchMessage {
+        message_id: String,
+        source: Box<ContractError>,
+    },
+}
@@ -1,3 +0,0 @@
-///! These are helpers stolen from a more up-to-date version of cw-storage-plus.
-pub(crate) mod de;
-pub(crate) mod int_key;
@@ -1,7 +1,11 @@
 pub mod contract;
-mod helpers;
+pub mod error;
 pub mod msg;
+mod shares;
+#[cfg(any(test, feature = "signer"))]
+pub mod signer;
 pub mod state;
+mod transaction;
 mod validation;
 
 #[cfg(test)]
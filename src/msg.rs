@@ -1,13 +1,17 @@
-use crate::helpers::de::KeyDeserialize;
-use crate::validation::{PubKey, Signature};
-use cosmwasm_std::{Addr, Coin, StdResult, Uint128};
-use cw_storage_plus::{Prefixer, PrimaryKey};
+pub use crate::validation::{PubKey, Signature};
+use cosmwasm_std::{Addr, Binary, Coin, Decimal, StdResult, Uint128};
+use cw_storage_plus::{Key, KeyDeserialize, Prefixer, PrimaryKey};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InstantiateMsg {
     pub valset: Vec<Validator>,
+    /// The fraction of the total stake that signatures must exceed, at least one half
+    /// and less than one. Defaults to one half.
+    pub threshold: Option<Decimal>,
+    /// The denominations accepted for deposits, or `None` to accept any.
+    pub denoms: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -20,19 +24,22 @@ pub struct Validator {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct JobId(pub String);
 
-// impl `PrimaryKey` and `Prefixer` for use as a key in `Map`s.
-impl<'a> PrimaryKey<'a> for &'a JobId {
+// impl `PrimaryKey`, `Prefixer` and `KeyDeserialize` for use as a key in `Map`s. The
+// borrowed `&JobId` keys use cw-storage-plus's blanket impls for references.
+impl<'a> PrimaryKey<'a> for JobId {
     type Prefix = ();
     type SubPrefix = ();
+    type Suffix = Self;
+    type SuperSuffix = Self;
 
-    fn key(&self) -> Vec<&[u8]> {
-        vec![self.0.as_bytes()]
+    fn key(&self) -> Vec<Key<'_>> {
+        vec![Key::Ref(self.0.as_bytes())]
     }
 }
 
-impl<'a> Prefixer<'a> for &'a JobId {
-    fn prefix(&self) -> Vec<&[u8]> {
-        vec![self.0.as_bytes()]
+impl<'a> Prefixer<'a> for JobId {
+    fn prefix(&self) -> Vec<Key<'_>> {
+        vec![Key::Ref(self.0.as_bytes())]
     }
 }
 
@@ -40,45 +47,309 @@ impl KeyDeserialize for JobId {
     type Output = JobId;
 
     fn from_vec(value: Vec<u8>) -> StdResult<Self::Output> {
-        Ok(JobId(<String as KeyDeserialize>::from_vec(value)?))
+        Ok(JobId(String::from_vec(value)?))
+    }
+}
+
+impl KeyDeserialize for &JobId {
+    type Output = JobId;
+
+    fn from_vec(value: Vec<u8>) -> StdResult<Self::Output> {
+        JobId::from_vec(value)
     }
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+/// Only `Deposit` and `DepositFor` accept funds; any other message sent with funds fails.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
     Deposit {
         job_id: JobId,
     },
+    /// Deposit the sent funds to `job_id` on behalf of `beneficiary`, who may then
+    /// withdraw them.
+    DepositFor {
+        job_id: JobId,
+        beneficiary: Addr,
+    },
     Withdraw {
         withdraw_info: Vec<JobInfo>,
     },
+    /// Move `coin` from the sender's balance for `from_job` to their balance for `to_job`.
+    MoveBalance {
+        from_job: JobId,
+        to_job: JobId,
+        coin: Coin,
+    },
+    /// Transfer `coin` from the sender's balance for `job_id` to `to`'s balance for it.
+    TransferBalance {
+        to: Addr,
+        job_id: JobId,
+        coin: Coin,
+    },
     WithConsensus {
         message_id: String,
-        raw_json: String,
+        /// A [`ConsensusMsg`] in its canonical JSON encoding, as produced by
+        /// [`QueryMsg::GetSigningBytes`]: no whitespace, and the keys of every object
+        /// sorted, as in RFC 8785. Any other encoding is rejected.
+        payload: Binary,
+        /// The block height the validators signed at, covered by their signatures.
+        /// Untrusted relayers must supply it when there is a priority window.
+        signed_at: Option<u64>,
+        /// Proves the message is part of a batch whose Merkle root the validators signed,
+        /// in which case `signatures` are over the root. Once a root has been verified
+        /// its signatures may be omitted.
+        proof: Option<MerkleProof>,
         signatures: Vec<Signature>,
     },
+    /// Apply several consensus messages in order, in a single transaction.
+    WithConsensusBatch {
+        messages: Vec<SignedMessage>,
+    },
+    /// Send the sender every relayer fee they have earned so far.
+    ClaimRewards {},
+}
+
+/// One message of an [`ExecuteMsg::WithConsensusBatch`], with the same fields as
+/// [`ExecuteMsg::WithConsensus`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SignedMessage {
+    pub message_id: String,
+    pub payload: Binary,
+    pub signed_at: Option<u64>,
+    pub proof: Option<MerkleProof>,
+    pub signatures: Vec<Signature>,
+    /// Skip this message if it fails, rather than failing the whole batch. A skipped
+    /// message changes nothing, and may be relayed again. This covers the target of an
+    /// [`ConsensusMsg::ExecuteJob`] failing, after the batch itself has succeeded.
+    #[serde(default)]
+    pub continue_on_error: bool,
+}
+
+/// The path from a message to the root of a Merkle tree of messages. Each leaf is the
+/// SHA-256 of `0x00` followed by the hash a lone message would be signed over, and each
+/// parent the SHA-256 of `0x01` followed by its two children in ascending order.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MerkleProof {
+    pub root: Binary,
+    /// The sibling of each node on the path, starting from the leaf.
+    pub siblings: Vec<Binary>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ConsensusMsg {
     Stub {},
+    UpdateValset {
+        valset: Vec<Validator>,
+    },
+    /// Trust `address` to relay messages on behalf of the validator with `public_key`.
+    AddRelayer {
+        public_key: PubKey,
+        address: Addr,
+    },
+    /// Stop trusting `address`, which must currently relay for `public_key`.
+    RemoveRelayer {
+        public_key: PubKey,
+        address: Addr,
+    },
+    /// Execute `msg` on `contract` on behalf of `job_id`. The relayer fee, if any, is
+    /// charged to the job's deposits and credited to the submitting relayer. Depositors
+    /// pay it pro rata to their deposits of its denomination. Their balances are rounded
+    /// down, and the fractions of a unit this leaves stay in the job's funds, to be
+    /// shared by the depositors that remain.
+    ExecuteJob {
+        job_id: JobId,
+        contract: Addr,
+        msg: Binary,
+    },
+    /// Limit `job_id` to one run in each window of `schedule`, starting afresh, or lift
+    /// the limit with `None`.
+    SetJobSchedule {
+        job_id: JobId,
+        schedule: Option<Schedule>,
+    },
+    /// Set the fee paid to relayers for each executed job, or `None` for no fee.
+    SetRelayerFee {
+        fee: Option<Coin>,
+    },
+    /// Open relaying to any address, optionally keeping the first `priority_blocks`
+    /// after signing for trusted relayers.
+    SetRelayConfig {
+        permissionless: bool,
+        priority_blocks: u64,
+    },
+    /// Freeze or unfreeze deposits, withdrawals and job execution. Consensus messages
+    /// that govern the contract itself are never paused.
+    SetPaused {
+        deposits: bool,
+        withdrawals: bool,
+        jobs: bool,
+    },
+}
+
+/// Windows of `interval` each, the first beginning at `start`. The job may run once in
+/// each window, until `end`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Schedule {
+    pub interval: Interval,
+    /// The block height or time in seconds, as `interval` counts, of the first window.
+    pub start: u64,
+    /// The block height or time in seconds from which the job may no longer run.
+    pub end: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Interval {
+    Blocks(u64),
+    Seconds(u64),
+}
+
+/// Overrides for the host chain's governance, to recover the contract should the valset
+/// lose its keys or no longer be able to reach consensus.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SudoMsg {
+    /// Replace the valset, as [`ConsensusMsg::UpdateValset`] would.
     UpdateValset { valset: Vec<Validator> },
+    /// Freeze or unfreeze deposits, withdrawals and job execution, as
+    /// [`ConsensusMsg::SetPaused`] would.
+    SetPaused {
+        deposits: bool,
+        withdrawals: bool,
+        jobs: bool,
+    },
+}
+
+/// Sent with a code upgrade. Rebuilds the storage layout of earlier versions in place.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct MigrateMsg {
+    /// The validator each trusted address relays for, when migrating from a version that
+    /// kept the addresses in a bare list. Only needed with more than one validator, as
+    /// every address otherwise relays for the only one.
+    #[serde(default)]
+    pub relayers: Vec<Relayer>,
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+/// An address trusted to relay for the validator with `public_key`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Relayer {
+    pub public_key: PubKey,
+    pub address: Addr,
+}
+
+/// Listings are paged: a page holds 10 entries unless `limit` says otherwise, `limit` is
+/// clamped to between 1 and 30, and `next_cursor` is given, to pass as `start_after`,
+/// while there are more.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
-    GetDepositInfo { address: Addr },
+    /// Every deposit held by `address`, ordered by denomination within each job. Jobs
+    /// come in storage order: shorter `JobId`s first, and those of equal length by their
+    /// bytes. `limit` counts jobs, so a page never splits the coins held for one job.
+    GetDepositInfo {
+        address: Addr,
+        start_after: Option<JobId>,
+        limit: Option<u32>,
+    },
+    /// The funds deposited to `job_id` and not yet charged, sorted by denomination. They
+    /// may be a little more than its depositors' balances, which are rounded down.
     GetJobInfo { job_id: JobId },
+    /// List every funded job, in storage order: shorter `JobId`s first, and those of
+    /// equal length by their bytes.
+    ListJobs {
+        start_after: Option<JobId>,
+        limit: Option<u32>,
+    },
+    /// Every deposit to `job_id`, ordered by denomination within each depositor.
+    /// Depositors come in storage order: shorter addresses first, and those of equal
+    /// length by their bytes. `limit` counts addresses, so a page never splits one
+    /// depositor's coins.
+    GetJobDepositors {
+        job_id: JobId,
+        start_after: Option<Addr>,
+        limit: Option<u32>,
+    },
+    /// Every scheduled job that may run in the current block, ordered by `JobId`. Jobs
+    /// whose funds can't pay the relayer fee are left out, and while jobs are paused
+    /// none are listed. `limit` bounds the schedules scanned rather than the jobs
+    /// returned, so a page may hold fewer jobs, or none, while `next_cursor` shows there
+    /// are more to scan.
+    DueJobs {
+        start_after: Option<JobId>,
+        limit: Option<u32>,
+    },
+    /// The live configuration of the contract.
+    GetConfig {},
+    /// Relayer fees earned by `address` and not yet claimed, sorted by denomination.
+    GetRelayerRewards { address: Addr },
+    /// Dry-run the checks a [`ExecuteMsg::WithConsensus`] with these fields would go
+    /// through, optionally as relayed by `relayer`, and report on each.
+    SimulateConsensus {
+        message_id: String,
+        payload: Binary,
+        signed_at: Option<u64>,
+        proof: Option<MerkleProof>,
+        signatures: Vec<Signature>,
+        relayer: Option<Addr>,
+    },
+    /// The canonical payload of `msg`, and the hash validators sign to approve it.
+    GetSigningBytes {
+        message_id: String,
+        msg: ConsensusMsg,
+        signed_at: Option<u64>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-#[serde(rename_all = "snake_case")]
-pub enum QueryResult {
-    Jobs(Vec<JobInfo>),
-    Balance(Vec<Coin>),
+pub struct DepositInfoResponse {
+    pub address: Addr,
+    pub deposits: Vec<JobInfo>,
+    /// The `start_after` of the next page, if there are more entries.
+    pub next_cursor: Option<JobId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct JobInfoResponse {
+    pub job_id: JobId,
+    pub funds: Vec<Coin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ListJobsResponse {
+    pub jobs: Vec<JobSummary>,
+    /// The `start_after` of the next page, if there are more entries.
+    pub next_cursor: Option<JobId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct JobDepositorsResponse {
+    pub job_id: JobId,
+    pub depositors: Vec<DepositorInfo>,
+    /// The `start_after` of the next page, if there are more entries.
+    pub next_cursor: Option<Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DueJobsResponse {
+    pub jobs: Vec<DueJob>,
+    /// The `start_after` of the next page, if there are more entries.
+    pub next_cursor: Option<JobId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DueJob {
+    pub job_id: JobId,
+    pub schedule: Schedule,
+    /// The start of the window the job may run in now.
+    pub window: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RelayerRewardsResponse {
+    pub address: Addr,
+    pub rewards: Vec<Coin>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -86,3 +357,60 @@ pub struct JobInfo {
     pub coin: Coin,
     pub job_id: JobId,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct JobSummary {
+    pub job_id: JobId,
+    /// The funds deposited to this job, summed and sorted by denomination.
+    pub funds: Vec<Coin>,
+    /// The number of distinct addresses holding a deposit for this job.
+    pub depositors: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DepositorInfo {
+    pub address: Addr,
+    pub coin: Coin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SigningBytes {
+    /// The `payload` to submit with the message.
+    pub payload: Binary,
+    /// The SHA-256 hash each validator signs with their secp256k1 key. It is taken over
+    /// `message_id`, `payload` and the big-endian `u64` `signed_at`, each preceded by its
+    /// length as a big-endian `u64`, with an absent `signed_at` left empty.
+    pub hash: Binary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ConsensusSimulation {
+    pub message_id_used: bool,
+    /// Why the payload would be rejected, if it would be.
+    pub payload_error: Option<String>,
+    /// Why the relayer would be rejected, if one was given and it would be.
+    pub relayer_error: Option<String>,
+    /// Whether the Merkle proof leads to its root, if there is one.
+    pub proof_valid: Option<bool>,
+    /// Whether the Merkle root was already verified against the current valset, in which
+    /// case no signatures are needed.
+    pub root_verified: bool,
+    pub signatures: Vec<SignatureCheck>,
+    /// The stake behind the valid signatures of known validators, each counted once.
+    pub signed_stake: Uint128,
+    pub total_stake: Uint128,
+    /// Whether the message is proven signed: by a valid proof under a verified root, or
+    /// by signatures carrying more than the threshold of the stake.
+    pub threshold_reached: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SignatureCheck {
+    pub pubkey: PubKey,
+    /// Whether `pubkey` belongs to a validator in the current valset.
+    pub known: bool,
+    pub valid: bool,
+    /// Whether this signature is valid, but one for `pubkey` was already counted.
+    pub duplicate: bool,
+    pub stake: Uint128,
+}
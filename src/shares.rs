@@ -0,0 +1,149 @@
+//! Job funds held in shares, so charging a job a fee is a single write however many
+//! depositors it has.
+//!
+//! The depositors of a job in one denomination hold shares in its funds, each worth the
+//! funds divided by the shares issued. Deposits issue shares at that price and
+//! withdrawals cancel them, while a fee takes from the funds alone, so every depositor
+//! pays it pro rata to their balance.
+//!
+//! Fees only ever lower the price of a share, so the shares issued per unit of funds
+//! grow without bound. Once they reach 2^96, the job moves to a new epoch, whose share is
+//! 2^32 of the last one's. Each deposit is counted in the share of the epoch it was last
+//! written in, and converted when read.
+use crate::msg::JobId;
+use crate::state::{Shares, JOB_SHARES, JOB_TOTALS};
+use cosmwasm_std::{StdResult, Storage, Uint128, Uint256, Uint512};
+
+/// How many bits smaller each epoch's share is than the last one's.
+const EPOCH_BITS: u32 = 32;
+/// The shares issued per unit deposited while none are outstanding, in bits. A share is
+/// then worth at most 2^-64 of a unit, which bounds what rounding the shares can lose.
+const INITIAL_BITS: u32 = 64;
+/// The shares per unit of funds, in bits, at which a job moves to a new epoch.
+const RESCALE_BITS: u32 = 96;
+/// Enough epochs to shift any number of shares down to none.
+const VOID_EPOCHS: u32 = 256 / EPOCH_BITS;
+
+/// The funds of a job in one denomination, and the shares issued in them.
+pub(crate) struct Pool {
+    pub funds: Uint128,
+    pub issued: Shares,
+}
+
+impl Pool {
+    pub fn load(storage: &dyn Storage, job_id: &JobId, denom: &str) -> StdResult<Pool> {
+        Ok(Pool {
+            funds: JOB_TOTALS
+                .may_load(storage, (job_id, denom))?
+                .unwrap_or_default(),
+            issued: JOB_SHARES
+                .may_load(storage, (job_id, denom))?
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn save(&self, storage: &mut dyn Storage, job_id: &JobId, denom: &str) -> StdResult<()> {
+        if self.funds.is_zero() {
+            JOB_TOTALS.remove(storage, (job_id, denom));
+        } else {
+            JOB_TOTALS.save(storage, (job_id, denom), &self.funds)?;
+        }
+        JOB_SHARES.save(storage, (job_id, denom), &self.issued)
+    }
+
+    /// What `deposit` is worth, rounded down, except that within 2^-32 of a unit rounds up
+    /// so the rounding of shares never shows in a balance.
+    pub fn balance(&self, deposit: &Shares) -> Uint128 {
+        self.value(self.current(deposit))
+    }
+
+    /// Add `amount` to `deposit`, issuing shares for it at their price, and return the
+    /// deposit as it now stands.
+    pub fn deposit(&mut self, deposit: Option<&Shares>, amount: Uint128) -> StdResult<Shares> {
+        if self.funds.is_zero() && !self.issued.shares.is_zero() {
+            // Fees have taken every unit, so the shares outstanding are worth nothing.
+            self.issued = Shares {
+                shares: Uint256::zero(),
+                epoch: self.issued.epoch + VOID_EPOCHS,
+            };
+        }
+        let held = deposit.map_or_else(Uint256::zero, |deposit| self.current(deposit));
+        let issued = if self.issued.shares.is_zero() {
+            Uint256::from(amount) << INITIAL_BITS
+        } else {
+            Uint256::try_from(
+                Uint512::from(amount) * Uint512::from(self.issued.shares)
+                    / Uint512::from(self.funds),
+            )?
+        };
+        self.funds = self.funds.checked_add(amount)?;
+        self.issued.shares = self.issued.shares.checked_add(issued)?;
+        Ok(Shares {
+            shares: held.checked_add(issued)?,
+            epoch: self.issued.epoch,
+        })
+    }
+
+    /// Take `amount`, which must not be more than its balance, from `deposit`, cancelling
+    /// the shares it is worth. Returns what is left of the deposit, unless it is worth
+    /// nothing, in which case the shares left are cancelled too.
+    pub fn withdraw(&mut self, deposit: &Shares, amount: Uint128) -> StdResult<Option<Shares>> {
+        let held = self.current(deposit);
+        let cancelled = if amount.is_zero() {
+            Uint256::zero()
+        } else {
+            // Round up, but never past the shares held, whose balance may have rounded up.
+            let issued = Uint512::from(self.issued.shares);
+            let funds = Uint512::from(self.funds);
+            let cancelled = (Uint512::from(amount) * issued + funds - Uint512::one()) / funds;
+            Uint256::try_from(cancelled)?.min(held)
+        };
+        self.funds = self.funds.checked_sub(amount)?;
+        self.issued.shares = self.issued.shares.checked_sub(cancelled)?;
+        let left = held - cancelled;
+        if self.value(left).is_zero() {
+            // Whatever sliver of a unit the shares were worth goes to the other depositors.
+            self.issued.shares = self.issued.shares.checked_sub(left)?;
+            return Ok(None);
+        }
+        Ok(Some(Shares {
+            shares: left,
+            epoch: self.issued.epoch,
+        }))
+    }
+
+    /// Take `amount` from the funds, lowering the price of every share.
+    pub fn charge(&mut self, amount: Uint128) -> StdResult<()> {
+        self.funds = self.funds.checked_sub(amount)?;
+        // With no funds left the shares are void, and the next deposit starts afresh.
+        let funds = Uint512::from(self.funds);
+        while !funds.is_zero() && Uint512::from(self.issued.shares) >= funds << RESCALE_BITS {
+            self.issued.shares >>= EPOCH_BITS;
+            self.issued.epoch += 1;
+        }
+        Ok(())
+    }
+
+    /// Return `amount` charged to the funds, raising the price of every share.
+    pub fn refund(&mut self, amount: Uint128) -> StdResult<()> {
+        self.funds = self.funds.checked_add(amount)?;
+        Ok(())
+    }
+
+    /// `deposit` counted in the share of the current epoch.
+    fn current(&self, deposit: &Shares) -> Uint256 {
+        let shift = (self.issued.epoch.saturating_sub(deposit.epoch)).saturating_mul(EPOCH_BITS);
+        deposit.shares.checked_shr(shift).unwrap_or_default()
+    }
+
+    fn value(&self, shares: Uint256) -> Uint128 {
+        if self.issued.shares.is_zero() {
+            return Uint128::zero();
+        }
+        let issued = Uint512::from(self.issued.shares);
+        let value =
+            (Uint512::from(shares) * Uint512::from(self.funds) + (issued >> EPOCH_BITS)) / issued;
+        // Shares are never issued past the funds, so this is at most the funds.
+        Uint128::try_from(value).map_or(self.funds, |value| value.min(self.funds))
+    }
+}
@@ -0,0 +1,82 @@
+//! Producing the signatures validators submit with consensus messages, off-chain.
+use crate::msg::{ConsensusMsg, ExecuteMsg, SignedMessage};
+use crate::validation::{canonical_payload, hash, root_hash, PubKey, Signature};
+use cosmwasm_std::Binary;
+use secp256k1::{Message, PublicKey, SecretKey, SECP256K1};
+
+/// The public key of `key`, as it appears in the valset.
+pub fn public_key(key: &SecretKey) -> PubKey {
+    PubKey(Binary::from(
+        PublicKey::from_secret_key(SECP256K1, key).serialize(),
+    ))
+}
+
+/// Sign a 32 byte `hash`, such as the one returned by [`QueryMsg::GetSigningBytes`], with
+/// each of `keys`.
+///
+/// [`QueryMsg::GetSigningBytes`]: crate::msg::QueryMsg::GetSigningBytes
+pub fn sign_hash(keys: &[SecretKey], hash: &[u8]) -> Result<Vec<Signature>, secp256k1::Error> {
+    let message = Message::from_slice(hash)?;
+    Ok(keys
+        .iter()
+        .map(|key| Signature {
+            pubkey: public_key(key),
+            signature: Binary::from(
+                SECP256K1
+                    .sign_ecdsa(&message, key)
+                    .serialize_compact()
+                    .to_vec(),
+            ),
+        })
+        .collect())
+}
+
+/// Sign `payload`, taken as is, with each of `keys`.
+pub fn sign(
+    keys: &[SecretKey],
+    message_id: &str,
+    payload: &[u8],
+    signed_at: Option<u64>,
+) -> Vec<Signature> {
+    sign_hash(keys, &hash(message_id, payload, signed_at)).expect("hashes are 32 bytes")
+}
+
+/// Sign a Merkle `root`, approving every message proven to be under it.
+pub fn sign_root(keys: &[SecretKey], root: &[u8]) -> Vec<Signature> {
+    sign_hash(keys, &root_hash(root)).expect("hashes are 32 bytes")
+}
+
+/// Encode `msg` canonically and sign it with each of `keys`.
+pub fn sign_message(
+    keys: &[SecretKey],
+    message_id: &str,
+    msg: &ConsensusMsg,
+    signed_at: Option<u64>,
+) -> serde_json::Result<SignedMessage> {
+    let payload = canonical_payload(msg)?;
+    Ok(SignedMessage {
+        message_id: message_id.to_string(),
+        signatures: sign(keys, message_id, &payload, signed_at),
+        payload: Binary::from(payload),
+        signed_at,
+        proof: None,
+        continue_on_error: false,
+    })
+}
+
+/// An [`ExecuteMsg::WithConsensus`] applying `msg`, signed with each of `keys`.
+pub fn with_consensus(
+    keys: &[SecretKey],
+    message_id: &str,
+    msg: &ConsensusMsg,
+    signed_at: Option<u64>,
+) -> serde_json::Result<ExecuteMsg> {
+    let message = sign_message(keys, message_id, msg, signed_at)?;
+    Ok(ExecuteMsg::WithConsensus {
+        message_id: message.message_id,
+        payload: message.payload,
+        signed_at: message.signed_at,
+        proof: message.proof,
+        signatures: message.signatures,
+    })
+}
@@ -1,17 +1,146 @@
-use crate::msg::JobId;
-use cosmwasm_std::{Addr, Uint128};
-use cw_storage_plus::Map;
+use crate::msg::{JobId, Schedule};
+use cosmwasm_std::{Addr, Coin, Decimal, Uint128, Uint256};
+use cw_storage_plus::{Index, IndexList, IndexedMap, Item, KeyDeserialize, Map, MultiIndex};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-pub struct State {
-    pub count: i32,
-    pub owner: Addr,
+pub struct Config {
+    /// The fraction of the total stake that validator signatures must exceed to make
+    /// consensus.
+    pub threshold: Decimal,
+    pub paused: Paused,
+    /// The denominations accepted for deposits, or `None` to accept any.
+    pub denoms: Option<Vec<String>>,
+    /// The fee paid to the relayer of each job, drawn from the job's deposits.
+    pub relayer_fee: Option<Coin>,
+    /// Incremented on every valset update.
+    pub valset_id: u64,
+    pub relay: RelayConfig,
 }
 
-/// Deposits indexed by `(address, job_id, denomination)`.
-pub const BALANCES: Map<(&Addr, &JobId, &str), Uint128> = Map::new("balances");
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            threshold: Decimal::percent(50),
+            paused: Paused::default(),
+            denoms: None,
+            relayer_fee: None,
+            valset_id: 0,
+            relay: RelayConfig::default(),
+        }
+    }
+}
+
+pub const CONFIG: Item<Config> = Item::new("config");
+
+/// Which operations are currently frozen by consensus.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct Paused {
+    pub deposits: bool,
+    pub withdrawals: bool,
+    pub jobs: bool,
+}
+
+/// Who may relay consensus messages.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct RelayConfig {
+    /// Accept consensus messages from any address, not only trusted relayers. The
+    /// validator signatures are what prove consensus either way.
+    pub permissionless: bool,
+    /// When permissionless, only trusted relayers may submit a message during this many
+    /// blocks after the height it was signed at.
+    pub priority_blocks: u64,
+}
+
+/// The key of a deposit, `(address, job_id, denomination)`.
+pub type BalanceKey<'a> = (&'a Addr, &'a JobId, &'a str);
+
+/// A number of shares in a job's funds of one denomination, counted in the unit of
+/// `epoch`. See [`crate::shares`] for what they are worth.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct Shares {
+    pub shares: Uint256,
+    pub epoch: u32,
+}
+
+pub struct BalanceIndexes<'a> {
+    /// Every deposit for a job, ordered by length-prefixed address and then by
+    /// denomination.
+    pub job_id: MultiIndex<'a, JobId, Shares, BalanceKey<'a>>,
+}
+
+impl<'a> IndexList<Shares> for BalanceIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<Shares>> + '_> {
+        let v: Vec<&dyn Index<Shares>> = vec![&self.job_id];
+        Box::new(v.into_iter())
+    }
+}
+
+/// The shares held in each deposit, indexed by `(address, job_id, denomination)`, and
+/// by `job_id`.
+pub fn balances<'a>() -> IndexedMap<'a, BalanceKey<'a>, Shares, BalanceIndexes<'a>> {
+    IndexedMap::new(
+        "balances",
+        BalanceIndexes {
+            job_id: MultiIndex::new(balance_job_id, "balances", "balances__job_id"),
+        },
+    )
+}
+
+fn balance_job_id(pk: &[u8], _: &Shares) -> JobId {
+    // Only ever called with keys the map itself encoded.
+    let (_, job_id, _) = BalanceKey::from_slice(pk).expect("malformed balance key");
+    job_id
+}
+
+/// Deposits as versions before 0.2.0 kept them, by amount rather than in shares, left for
+/// [`crate::contract::migrate`] to convert.
+pub const LEGACY_BALANCES: Map<BalanceKey, Uint128> = Map::new("balances");
+
+/// The hand-maintained reverse index on balances that preceded [`BalanceIndexes`], left
+/// for [`crate::contract::migrate`] to delete.
+pub const LEGACY_BALANCES_BY_JOB_ID: Map<(&JobId, &Addr, &str), ()> =
+    Map::new("balances_by_job_id");
+
+/// The funds of each `(job_id, denomination)`, which its depositors share in.
+pub const JOB_TOTALS: Map<(&JobId, &str), Uint128> = Map::new("job_totals");
+
+/// The shares issued in the funds of each `(job_id, denomination)`. Kept once issued,
+/// even when none are left, so the epoch of every deposit stays meaningful.
+pub const JOB_SHARES: Map<(&JobId, &str), Shares> = Map::new("job_shares");
+
+/// Fees earned by relayers, indexed by `(address, denomination)`, awaiting a claim.
+pub const RELAYER_REWARDS: Map<(&Addr, &str), Uint128> = Map::new("relayer_rewards");
+
+/// A job's [`Schedule`], with the window it last ran in.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct JobSchedule {
+    pub schedule: Schedule,
+    /// The start of the window the job last ran in, if it has run.
+    pub last_window: Option<u64>,
+}
+
+/// The schedules of the jobs that have one. Jobs without may run at any time.
+pub const JOB_SCHEDULES: Map<&JobId, JobSchedule> = Map::new("job_schedules");
+
+/// What running a job from a message that may be skipped changed, for
+/// [`crate::contract::reply`] to undo should the job's target fail.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingJob {
+    pub message_id: String,
+    pub job_id: JobId,
+    pub relayer: Addr,
+    /// The window the run was marked in, if the job is scheduled, and the one before it.
+    pub window: Option<u64>,
+    pub previous_window: Option<u64>,
+    /// The relayer fee charged to the job's funds.
+    pub fee: Option<Coin>,
+}
+
+/// Jobs dispatched from skippable messages, by reply id. Only failures are replied to,
+/// so the entries of jobs that ran are cleared by the next batch.
+pub const PENDING_JOBS: Map<u64, PendingJob> = Map::new("pending_jobs");
 
-/// A reverse index on [`BALANCES`].
-pub const BALANCES_BY_JOB_ID: Map<(&JobId, &Addr, &str), ()> = Map::new("balances_by_job_id");
+/// The reply id for the next entry of [`PENDING_JOBS`].
+pub const NEXT_REPLY_ID: Item<u64> = Item::new("next_reply_id");
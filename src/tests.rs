@@ -1,14 +1,40 @@
-use crate::contract::{execute, instantiate};
+use crate::contract::{execute, instantiate, migrate, reply, sudo};
+use crate::error::ContractError;
 use crate::msg::{
-    ConsensusMsg, ExecuteMsg, InstantiateMsg, JobId, JobInfo, QueryMsg, QueryResult, Validator,
+    ConsensusMsg, ConsensusSimulation, DepositInfoResponse, DepositorInfo, DueJob, DueJobsResponse,
+    ExecuteMsg, InstantiateMsg, Interval, JobDepositorsResponse, JobId, JobInfo, JobInfoResponse,
+    JobSummary, ListJobsResponse, MerkleProof, MigrateMsg, QueryMsg, Relayer,
+    RelayerRewardsResponse, Schedule, SignatureCheck, SignedMessage, SigningBytes, SudoMsg,
+    Validator,
 };
+use crate::shares::Pool;
+use crate::signer;
+use crate::state::{
+    balances, Config, Paused, RelayConfig, JOB_SHARES, JOB_TOTALS, RELAYER_REWARDS,
+};
+use crate::transaction::Transaction;
 use crate::validation;
-use crate::validation::{PubKey, Signature};
-use cosmwasm_std::testing::{mock_dependencies_with_balances, mock_env, mock_info};
-use cosmwasm_std::{from_binary, Addr, Api, Binary, Coin, Deps, DepsMut, Env, Uint128};
+use crate::validation::{PubKey, Signature, ValKey, LEGACY_TRUSTED_ADDRESSES};
+use cosmwasm_std::testing::{
+    mock_dependencies_with_balances, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+};
+use cosmwasm_std::{
+    from_json, Addr, Api, BankMsg, Binary, CanonicalAddr, Coin, CosmosMsg, Decimal, Deps, DepsMut,
+    Env, Order, OwnedDeps, RecoverPubkeyError, Reply, ReplyOn, Response, StdResult, Storage,
+    SubMsgResult, Uint128, Uint256, VerificationError, WasmMsg,
+};
+use cw_storage_plus::{Item, Map};
 use eyre::Result;
+use proptest::prelude::*;
 use secp256k1::rand::thread_rng;
-use secp256k1::{generate_keypair, Message, SecretKey};
+use secp256k1::SecretKey;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+type MockDeps = OwnedDeps<MockStorage, MockApi, MockQuerier>;
+type Keys = Vec<(SecretKey, PubKey)>;
 
 fn job_id(id: i32) -> JobId {
     JobId(id.to_string())
@@ -22,20 +48,78 @@ fn coin2(amount: u128) -> Coin {
     cosmwasm_std::coin(amount, "🐥")
 }
 
-pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<QueryResult> {
-    Ok(from_binary(&crate::contract::query(deps, env, msg)?)?)
+pub fn query<T: DeserializeOwned>(deps: Deps, env: Env, msg: QueryMsg) -> Result<T> {
+    Ok(from_json(&crate::contract::query(deps, env, msg)?)?)
 }
 
-#[test]
-fn simple_deposit_query_withdraw() -> Result<()> {
-    let mut deps = mock_dependencies_with_balances(&[]);
+fn gen_keys() -> (SecretKey, PubKey) {
+    let privkey = SecretKey::new(&mut thread_rng());
+    let pubkey = signer::public_key(&privkey);
+    (privkey, pubkey)
+}
 
-    let _ = instantiate(
+fn secret_keys(keys: &[(SecretKey, PubKey)]) -> Vec<SecretKey> {
+    keys.iter().map(|(privkey, _)| *privkey).collect()
+}
+
+fn sign_hash(keys: &[(SecretKey, PubKey)], hash: &[u8]) -> Result<Vec<Signature>> {
+    Ok(signer::sign_hash(&secret_keys(keys), hash)?)
+}
+
+fn sign(
+    keys: &[(SecretKey, PubKey)],
+    message_id: &str,
+    payload: &[u8],
+    signed_at: Option<u64>,
+) -> Vec<Signature> {
+    signer::sign(&secret_keys(keys), message_id, payload, signed_at)
+}
+
+/// Submit `msg` from `relayer`, signed by every one of `keys`.
+fn with_consensus(
+    deps: DepsMut,
+    relayer: &Addr,
+    keys: &[(SecretKey, PubKey)],
+    message_id: &str,
+    msg: &ConsensusMsg,
+) -> Result<Response> {
+    let msg = signer::with_consensus(&secret_keys(keys), message_id, msg, None)?;
+    // Go through JSON, as the output of the signer would.
+    let msg = serde_json::from_slice(&serde_json::to_vec(&msg)?)?;
+    Ok(execute(
+        deps,
+        mock_env(),
+        mock_info(relayer.as_str(), &[]),
+        msg,
+    )?)
+}
+
+/// Instantiate the contract with a single validator, of stake 1, relayed for by
+/// `relayer`. Returns the relayer and the validator's keys.
+fn setup() -> Result<(MockDeps, Addr, Keys)> {
+    let mut deps = mock_dependencies_with_balances(&[]);
+    let relayer = deps.api.addr_validate("relayer")?;
+    let keys = vec![gen_keys()];
+    instantiate(
         deps.as_mut(),
         mock_env(),
         mock_info("creator", &[]),
-        InstantiateMsg { valset: vec![] },
+        InstantiateMsg {
+            valset: vec![Validator {
+                public_key: keys[0].1.clone(),
+                stake: Uint128::new(1),
+                address: vec![relayer.clone()],
+            }],
+            threshold: None,
+            denoms: None,
+        },
     )?;
+    Ok((deps, relayer, keys))
+}
+
+#[test]
+fn simple_deposit_query_withdraw() -> Result<()> {
+    let (mut deps, ..) = setup()?;
 
     let addr_a = deps.api.addr_validate("aaa")?;
     let addr_b = deps.api.addr_validate("bbb")?;
@@ -59,45 +143,61 @@ fn simple_deposit_query_withdraw() -> Result<()> {
         )?;
     }
 
-    let qr = query(
-        deps.as_ref(),
-        mock_env(),
-        QueryMsg::GetDepositInfo {
-            address: addr_a.clone(),
-        },
-    )?;
+    let deposit_info = |deps: Deps, address: &Addr| -> Result<DepositInfoResponse> {
+        query(
+            deps,
+            mock_env(),
+            QueryMsg::GetDepositInfo {
+                address: address.clone(),
+                start_after: None,
+                limit: None,
+            },
+        )
+    };
+    // Repeated denominations in one deposit are merged, and zero coins leave no entry.
     assert_eq!(
-        qr,
-        QueryResult::Jobs(vec![
+        deposit_info(deps.as_ref(), &addr_c)?.deposits,
+        vec![
             JobInfo {
-                coin: coin(1000),
-                job_id: job_id(1)
+                coin: coin(10),
+                job_id: job_id(3),
             },
             JobInfo {
-                coin: coin2(777),
-                job_id: job_id(1),
+                coin: coin2(8),
+                job_id: job_id(3),
             },
-            JobInfo {
-                coin: coin(500),
-                job_id: job_id(2)
-            }
-        ])
+        ]
+    );
+    assert_eq!(
+        deposit_info(deps.as_ref(), &addr_a)?,
+        DepositInfoResponse {
+            address: addr_a.clone(),
+            deposits: vec![
+                JobInfo {
+                    coin: coin(1000),
+                    job_id: job_id(1)
+                },
+                JobInfo {
+                    coin: coin2(777),
+                    job_id: job_id(1),
+                },
+                JobInfo {
+                    coin: coin(500),
+                    job_id: job_id(2)
+                }
+            ],
+            next_cursor: None,
+        }
     );
 
-    let qr = query(
+    let qr: JobInfoResponse = query(
         deps.as_ref(),
         mock_env(),
         QueryMsg::GetJobInfo { job_id: job_id(1) },
     )?;
-    match qr {
-        QueryResult::Balance(mut coins) => {
-            coins.sort_by_key(|coin| coin.denom.clone());
-            assert_eq!(coins, vec![coin(7000), coin2(777)]);
-        }
-        _ => panic!("GetJobInfo must return a Balance"),
-    };
+    assert_eq!(qr.funds, vec![coin(7000), coin2(777)]);
 
-    execute(
+    let res = execute(
         deps.as_mut(),
         mock_env(),
         mock_info(addr_a.as_str(), &[]),
@@ -114,17 +214,18 @@ fn simple_deposit_query_withdraw() -> Result<()> {
             ],
         },
     )?;
+    // The withdrawn amounts are sent, not what remains.
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: addr_a.to_string(),
+            amount: vec![coin(14), coin(500)],
+        })
+    );
 
-    let qr = query(
-        deps.as_ref(),
-        mock_env(),
-        QueryMsg::GetDepositInfo {
-            address: addr_a.clone(),
-        },
-    )?;
     assert_eq!(
-        qr,
-        QueryResult::Jobs(vec![
+        deposit_info(deps.as_ref(), &addr_a)?.deposits,
+        vec![
             JobInfo {
                 coin: coin(986),
                 job_id: job_id(1)
@@ -133,212 +234,3034 @@ fn simple_deposit_query_withdraw() -> Result<()> {
                 coin: coin2(777),
                 job_id: job_id(1),
             },
-        ])
+        ]
     );
 
     Ok(())
 }
 
 #[test]
-fn deposit_withdraw_errors() -> Result<()> {
-    let mut deps = mock_dependencies_with_balances(&[]);
+fn job_totals() -> Result<()> {
+    let (mut deps, ..) = setup()?;
+    let totals = |deps: Deps| {
+        JOB_TOTALS
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()
+    };
+    let withdraw = |deps: DepsMut, amount: u128| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info("aaa", &[]),
+            ExecuteMsg::Withdraw {
+                withdraw_info: vec![JobInfo {
+                    coin: coin(amount),
+                    job_id: job_id(1),
+                }],
+            },
+        )
+    };
 
-    let _ = instantiate(
+    for address in ["aaa", "bbb"] {
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(address, &[coin(100)]),
+            ExecuteMsg::Deposit { job_id: job_id(1) },
+        )?;
+    }
+    assert_eq!(
+        totals(deps.as_ref())?,
+        vec![((job_id(1), coin(0).denom), Uint128::new(200))]
+    );
+
+    withdraw(deps.as_mut(), 40)?;
+    assert_eq!(
+        totals(deps.as_ref())?,
+        vec![((job_id(1), coin(0).denom), Uint128::new(160))]
+    );
+
+    // Emptying one balance leaves the other in the total.
+    withdraw(deps.as_mut(), 60)?;
+    assert_eq!(
+        totals(deps.as_ref())?,
+        vec![((job_id(1), coin(0).denom), Uint128::new(100))]
+    );
+    execute(
         deps.as_mut(),
         mock_env(),
-        mock_info("creator", &[]),
-        InstantiateMsg { valset: vec![] },
+        mock_info("bbb", &[]),
+        ExecuteMsg::Withdraw {
+            withdraw_info: vec![JobInfo {
+                coin: coin(100),
+                job_id: job_id(1),
+            }],
+        },
     )?;
+    // An emptied job leaves no total behind.
+    assert_eq!(totals(deps.as_ref())?, vec![]);
+
+    Ok(())
+}
+
+#[test]
+fn list_jobs() -> Result<()> {
+    let (mut deps, ..) = setup()?;
 
     let addr_a = deps.api.addr_validate("aaa")?;
+    let addr_b = deps.api.addr_validate("bbb")?;
     for (deposit, job_id) in [
-        // No empty deposits.
-        (mock_info(addr_a.as_str(), &[]), job_id(1)),
-        // Or zero deposits.
-        (mock_info(addr_a.as_str(), &[coin(0)]), job_id(2)),
+        (
+            mock_info(addr_a.as_str(), &[coin(600), coin2(777)]),
+            job_id(1),
+        ),
+        (mock_info(addr_b.as_str(), &[coin(6000)]), job_id(1)),
+        (mock_info(addr_a.as_str(), &[coin(500)]), job_id(2)),
+        (mock_info(addr_b.as_str(), &[coin2(3)]), job_id(3)),
     ] {
-        assert!(execute(
+        execute(
             deps.as_mut(),
             mock_env(),
             deposit,
             ExecuteMsg::Deposit { job_id },
-        )
-        .is_err());
+        )?;
     }
-    execute(
-        deps.as_mut(),
-        mock_env(),
-        mock_info(addr_a.as_str(), &[coin(1000)]),
-        ExecuteMsg::Deposit { job_id: job_id(1) },
-    )?;
 
-    for withdraw_info in [
-        // No withdrawing nothing.
-        vec![],
-        // Or too much.
-        vec![JobInfo {
-            coin: coin(2000),
-            job_id: job_id(1),
-        }],
-        // Or too much but piecemeal.
-        vec![
-            JobInfo {
-                coin: coin(501),
-                job_id: job_id(1),
-            },
-            JobInfo {
-                coin: coin(501),
-                job_id: job_id(1),
-            },
-        ],
-        // Or of the wrong denomination.
-        vec![JobInfo {
-            coin: coin2(1),
-            job_id: job_id(1),
-        }],
-    ] {
-        assert!(execute(
-            deps.as_mut(),
+    let list = |start_after: Option<JobId>, limit: Option<u32>| -> Result<_> {
+        let res: ListJobsResponse = query(
+            deps.as_ref(),
             mock_env(),
-            mock_info(addr_a.as_str(), &[]),
-            ExecuteMsg::Withdraw { withdraw_info },
-        )
-        .is_err());
-    }
+            QueryMsg::ListJobs { start_after, limit },
+        )?;
+        Ok((res.jobs, res.next_cursor))
+    };
+
+    let all_jobs = vec![
+        JobSummary {
+            job_id: job_id(1),
+            funds: vec![coin(6600), coin2(777)],
+            depositors: 2,
+        },
+        JobSummary {
+            job_id: job_id(2),
+            funds: vec![coin(500)],
+            depositors: 1,
+        },
+        JobSummary {
+            job_id: job_id(3),
+            funds: vec![coin2(3)],
+            depositors: 1,
+        },
+    ];
+    assert_eq!(list(None, None)?, (all_jobs.clone(), None));
+    // Paging picks up right after `start_after`, and the cursor is only given when
+    // there is more to come.
+    assert_eq!(
+        list(None, Some(2))?,
+        (all_jobs[..2].to_vec(), Some(job_id(2)))
+    );
+    assert_eq!(
+        list(Some(job_id(1)), Some(1))?,
+        (all_jobs[1..2].to_vec(), Some(job_id(2)))
+    );
+    assert_eq!(list(Some(job_id(2)), None)?, (all_jobs[2..].to_vec(), None));
+    assert_eq!(list(Some(job_id(3)), None)?, (vec![], None));
+    // A zero limit still makes progress rather than ending the listing.
+    assert_eq!(
+        list(None, Some(0))?,
+        (all_jobs[..1].to_vec(), Some(job_id(1)))
+    );
 
     Ok(())
 }
 
 #[test]
-fn simple_validation() -> Result<()> {
-    let mut deps = mock_dependencies_with_balances(&[]);
+fn job_depositors() -> Result<()> {
+    let (mut deps, ..) = setup()?;
 
-    fn gen_keys() -> (SecretKey, PubKey) {
-        let (privkey, pubkey) = generate_keypair(&mut thread_rng());
-        let pubkey = PubKey(Binary::from(pubkey.serialize()));
-        (privkey, pubkey)
+    let addr_a = deps.api.addr_validate("aaa")?;
+    let addr_b = deps.api.addr_validate("bbb")?;
+    let addr_c = deps.api.addr_validate("ccc")?;
+    for (deposit, job_id) in [
+        (
+            mock_info(addr_a.as_str(), &[coin(600), coin2(777)]),
+            job_id(1),
+        ),
+        (mock_info(addr_b.as_str(), &[coin(6000)]), job_id(1)),
+        (mock_info(addr_a.as_str(), &[coin(500)]), job_id(2)),
+        (mock_info(addr_c.as_str(), &[coin(1)]), job_id(1)),
+    ] {
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            deposit,
+            ExecuteMsg::Deposit { job_id },
+        )?;
     }
 
-    let mut base_message_id: u64 = 0xBA5EBA11 - 1;
-    // Generate a unique message id.
-    let mut mid = || -> String {
-        base_message_id += 1;
-        format!("{:x}", base_message_id)
+    let depositors = |start_after: Option<Addr>, limit: Option<u32>| -> Result<_> {
+        let res: JobDepositorsResponse = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::GetJobDepositors {
+                job_id: job_id(1),
+                start_after,
+                limit,
+            },
+        )?;
+        assert_eq!(res.job_id, job_id(1));
+        Ok((res.depositors, res.next_cursor))
     };
 
-    fn sign(
-        keys: &[(SecretKey, PubKey)],
-        message_id: &str,
-        raw_json: &str,
-    ) -> Result<Vec<Signature>> {
-        Ok(keys
-            .iter()
-            .map(|(privkey, pubkey)| {
-                Ok(Signature {
-                    pubkey: pubkey.clone(),
-                    signature: Binary::from(
-                        privkey
-                            .sign_ecdsa(Message::from_slice(&validation::hash(
-                                message_id, raw_json,
-                            ))?)
-                            .serialize_compact(),
-                    ),
-                })
-            })
-            .collect::<Result<Vec<_>>>()?)
-    }
-
-    let addresses: Vec<_> = [
-        "aaa", "bbb", "ccc", "ddd", "eee", "fff", "ggg", "hhh", "iii", "jjj",
-    ]
-    .into_iter()
-    .map(|addr| Ok(deps.api.addr_validate(addr)?))
-    .collect::<Result<_>>()?;
-    let non_validator = deps.api.addr_validate("eve")?;
-    let keys: Vec<_> = addresses.iter().map(|_| gen_keys()).collect();
-
-    let _ = instantiate(
-        deps.as_mut(),
-        mock_env(),
-        mock_info("creator", &[]),
-        InstantiateMsg {
-            valset: addresses
-                .iter()
-                .zip(&keys)
-                .map(|(addr, (_, pubkey))| Validator {
-                    public_key: pubkey.clone(),
-                    stake: Uint128::new(10),
-                    address: vec![addr.clone()],
-                })
-                .collect(),
+    let all_depositors = vec![
+        DepositorInfo {
+            address: addr_a.clone(),
+            coin: coin(600),
         },
-    )?;
+        DepositorInfo {
+            address: addr_a.clone(),
+            coin: coin2(777),
+        },
+        DepositorInfo {
+            address: addr_b.clone(),
+            coin: coin(6000),
+        },
+        DepositorInfo {
+            address: addr_c.clone(),
+            coin: coin(1),
+        },
+    ];
+    assert_eq!(depositors(None, None)?, (all_depositors.clone(), None));
+    // A page holds every coin of each depositor in it.
+    assert_eq!(
+        depositors(None, Some(1))?,
+        (all_depositors[..2].to_vec(), Some(addr_a.clone()))
+    );
+    assert_eq!(
+        depositors(Some(addr_a.clone()), Some(1))?,
+        (all_depositors[2..3].to_vec(), Some(addr_b.clone()))
+    );
+    assert_eq!(
+        depositors(Some(addr_b), None)?,
+        (all_depositors[3..].to_vec(), None)
+    );
+    assert_eq!(depositors(Some(addr_c), None)?, (vec![], None));
+    assert_eq!(
+        depositors(None, Some(0))?,
+        (all_depositors[..2].to_vec(), Some(addr_a.clone()))
+    );
 
-    let t = |deps: DepsMut,
-             addr: &Addr,
-             message_id: &str,
-             keys: &[(SecretKey, PubKey)]|
-     -> Result<()> {
-        let valid_json = r#"{"stub": {}}"#;
-        execute(
-            deps,
+    // Likewise for the jobs of a depositor.
+    let deposits = |start_after: Option<JobId>, limit: Option<u32>| -> Result<_> {
+        let res: DepositInfoResponse = query(
+            deps.as_ref(),
             mock_env(),
-            mock_info(addr.as_str(), &[]),
-            ExecuteMsg::WithConsensus {
-                message_id: message_id.to_string(),
-                raw_json: valid_json.to_string(),
-                signatures: sign(&keys, &message_id, &valid_json)?,
+            QueryMsg::GetDepositInfo {
+                address: addr_a.clone(),
+                start_after,
+                limit,
             },
         )?;
-        Ok(())
+        Ok((res.deposits, res.next_cursor))
     };
-
-    // No messages validate with less than quorum. Exactly half is not quorum.
-    for addr in [&addresses[0], &non_validator] {
-        for n in 0..=5 {
-            assert!(t(deps.as_mut(), addr, &mid(), &keys[..n]).is_err());
-        }
-    }
-    // Non trusted addreses never work.
-    for n in 0..=keys.len() {
-        assert!(t(deps.as_mut(), &non_validator, &mid(), &keys[..n]).is_err());
+    let all_deposits = [
+        JobInfo {
+            coin: coin(600),
+            job_id: job_id(1),
+        },
+        JobInfo {
+            coin: coin2(777),
+            job_id: job_id(1),
+        },
+        JobInfo {
+            coin: coin(500),
+            job_id: job_id(2),
+        },
+    ];
+    assert_eq!(
+        deposits(None, Some(1))?,
+        (all_deposits[..2].to_vec(), Some(job_id(1)))
+    );
+    assert_eq!(
+        deposits(Some(job_id(1)), Some(1))?,
+        (all_deposits[2..].to_vec(), None)
+    );
+    assert_eq!(
+        deposits(None, Some(0))?,
+        (all_deposits[..2].to_vec(), Some(job_id(1)))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn deposit_info_limits() -> Result<()> {
+    let (mut deps, ..) = setup()?;
+    for id in 0..40 {
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("aaa", &[coin(1)]),
+            ExecuteMsg::Deposit { job_id: job_id(id) },
+        )?;
+    }
+    let deposits = |limit: Option<u32>| -> Result<_> {
+        let res: DepositInfoResponse = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::GetDepositInfo {
+                address: Addr::unchecked("aaa"),
+                start_after: None,
+                limit,
+            },
+        )?;
+        Ok((res.deposits.len(), res.next_cursor.is_some()))
+    };
+
+    // Pages default and are capped like those of the other listings.
+    assert_eq!(deposits(None)?, (10, true));
+    assert_eq!(deposits(Some(100))?, (30, true));
+
+    Ok(())
+}
+
+#[test]
+fn list_jobs_storage_order() -> Result<()> {
+    let (mut deps, ..) = setup()?;
+    for (address, job_id) in [("aaa", "10"), ("bbb", "10"), ("aaa", "9"), ("aaa", "a")] {
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(address, &[coin(1)]),
+            ExecuteMsg::Deposit {
+                job_id: JobId(job_id.to_string()),
+            },
+        )?;
+    }
+    let list = |start_after: Option<&str>, limit: Option<u32>| -> Result<_> {
+        let res: ListJobsResponse = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::ListJobs {
+                start_after: start_after.map(|job_id| JobId(job_id.to_string())),
+                limit,
+            },
+        )?;
+        let jobs: Vec<_> = res.jobs.into_iter().map(|job| job.job_id.0).collect();
+        Ok((jobs, res.next_cursor.map(|job_id| job_id.0)))
+    };
+
+    // Shorter ids come first, whatever their bytes.
+    assert_eq!(
+        list(None, None)?,
+        (vec!["9".into(), "a".into(), "10".into()], None)
+    );
+    assert_eq!(
+        list(Some("9"), Some(1))?,
+        (vec!["a".into()], Some("a".into()))
+    );
+    assert_eq!(list(Some("a"), None)?, (vec!["10".into()], None));
+    assert_eq!(list(Some("10"), None)?, (vec![], None));
+
+    Ok(())
+}
+
+#[test]
+fn migrate_balance_index() -> Result<()> {
+    let mut deps = mock_dependencies_with_balances(&[]);
+    let relayer = deps.api.addr_validate("relayer")?;
+    let keys = vec![gen_keys()];
+
+    // Storage as the first release left it: a lone validator relayed for by a bare list
+    // of addresses, and deposits with a hand-maintained reverse index, entries for zero
+    // coins and no running totals. There was no configuration.
+    cw2::set_contract_version(&mut deps.storage, "crates.io:turnstone", "0.1.0")?;
+    let legacy_validators: Item<Vec<ValKey>> = Item::new("validators");
+    legacy_validators.save(
+        &mut deps.storage,
+        &vec![ValKey {
+            pubkey: keys[0].1.clone(),
+            stake: Uint128::new(1),
+        }],
+    )?;
+    let legacy_trusted_addresses: Item<Vec<Addr>> = Item::new("trusted_addreses");
+    legacy_trusted_addresses.save(&mut deps.storage, &vec![relayer.clone()])?;
+    let legacy_used_message_ids: Map<&str, ()> = Map::new("used_message_ids");
+    legacy_used_message_ids.save(&mut deps.storage, "used", &())?;
+    let legacy_balances: Map<(&Addr, &JobId, &str), Uint128> = Map::new("balances");
+    let legacy_index: Map<(&JobId, &Addr, &str), ()> = Map::new("balances_by_job_id");
+    let aaa = Addr::unchecked("aaa");
+    let bbb = Addr::unchecked("bbb");
+    for (address, job_id, coin) in [
+        (&aaa, job_id(1), coin(100)),
+        (&bbb, job_id(1), coin(50)),
+        (&bbb, job_id(1), coin2(0)),
+        (&aaa, job_id(2), coin2(7)),
+        (&bbb, job_id(3), coin(0)),
+    ] {
+        legacy_balances.save(
+            &mut deps.storage,
+            (address, &job_id, &coin.denom),
+            &coin.amount,
+        )?;
+        legacy_index.save(&mut deps.storage, (&job_id, address, &coin.denom), &())?;
+    }
+    let list_jobs = |deps: Deps| {
+        query::<ListJobsResponse>(
+            deps,
+            mock_env(),
+            QueryMsg::ListJobs {
+                start_after: None,
+                limit: None,
+            },
+        )
+    };
+
+    let res = migrate(deps.as_mut(), mock_env(), MigrateMsg::default())?;
+    for (key, value) in [
+        ("previous_version", "0.1.0"),
+        ("balances", "3"),
+        ("job_totals", "2"),
+        ("zero_balances_removed", "2"),
+    ] {
+        assert!(res
+            .attributes
+            .iter()
+            .any(|attr| attr.key == key && attr.value == value));
+    }
+    assert_eq!(
+        cw2::get_contract_version(&deps.storage)?.version,
+        env!("CARGO_PKG_VERSION")
+    );
+    assert_eq!(
+        JOB_TOTALS
+            .range(&deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?,
+        vec![
+            ((job_id(1), coin(0).denom), Uint128::new(150)),
+            ((job_id(2), coin2(0).denom), Uint128::new(7)),
+        ]
+    );
+    assert!(legacy_index.is_empty(&deps.storage));
+    assert!(legacy_trusted_addresses.may_load(&deps.storage)?.is_none());
+    assert_eq!(
+        query::<Config>(deps.as_ref(), mock_env(), QueryMsg::GetConfig {})?,
+        Config::default()
+    );
+    assert_eq!(
+        list_jobs(deps.as_ref())?.jobs,
+        vec![
+            JobSummary {
+                job_id: job_id(1),
+                funds: vec![coin(150)],
+                depositors: 2,
+            },
+            JobSummary {
+                job_id: job_id(2),
+                funds: vec![coin2(7)],
+                depositors: 1,
+            },
+        ]
+    );
+    let res: JobDepositorsResponse = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::GetJobDepositors {
+            job_id: job_id(1),
+            start_after: None,
+            limit: None,
+        },
+    )?;
+    assert_eq!(
+        res.depositors,
+        vec![
+            DepositorInfo {
+                address: aaa.clone(),
+                coin: coin(100),
+            },
+            DepositorInfo {
+                address: bbb.clone(),
+                coin: coin(50),
+            },
+        ]
+    );
+
+    // The relayer carries on for the validator, and used message ids stay used.
+    let res = with_consensus(deps.as_mut(), &relayer, &keys, "1", &ConsensusMsg::Stub {})?;
+    assert!(res
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "validator" && attr.value == keys[0].1 .0.to_base64()));
+    assert!(matches!(
+        with_consensus(
+            deps.as_mut(),
+            &relayer,
+            &keys,
+            "used",
+            &ConsensusMsg::Stub {}
+        )
+        .unwrap_err()
+        .downcast::<ContractError>()?,
+        ContractError::MessageIdUsed { .. }
+    ));
+
+    // Migrating again changes nothing.
+    let _ = migrate(deps.as_mut(), mock_env(), MigrateMsg::default())?;
+    assert_eq!(list_jobs(deps.as_ref())?.jobs.len(), 2);
+
+    // Emptied balances leave the index along with the map, and are paid out.
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(bbb.as_str(), &[]),
+        ExecuteMsg::Withdraw {
+            withdraw_info: vec![JobInfo {
+                job_id: job_id(1),
+                coin: coin(50),
+            }],
+        },
+    )?;
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: bbb.to_string(),
+            amount: vec![coin(50)],
+        })
+    );
+    assert_eq!(list_jobs(deps.as_ref())?.jobs[0].depositors, 1);
+
+    // Other contracts, and later versions of this one, are never migrated.
+    cw2::set_contract_version(&mut deps.storage, "crates.io:turnstone", "99.0.0")?;
+    assert!(matches!(
+        migrate(deps.as_mut(), mock_env(), MigrateMsg::default()),
+        Err(ContractError::Downgrade { from, .. }) if from == "99.0.0"
+    ));
+    cw2::set_contract_version(&mut deps.storage, "crates.io:other", "0.1.0")?;
+    assert!(matches!(
+        migrate(deps.as_mut(), mock_env(), MigrateMsg::default()),
+        Err(ContractError::WrongContract { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn deposit_for() -> Result<()> {
+    let (mut deps, ..) = setup()?;
+    let sponsor = deps.api.addr_validate("sponsor")?;
+    let user = deps.api.addr_validate("user")?;
+
+    // Malformed beneficiaries would strand the funds.
+    assert!(matches!(
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(sponsor.as_str(), &[coin(100)]),
+            ExecuteMsg::DepositFor {
+                job_id: job_id(1),
+                beneficiary: Addr::unchecked("User"),
+            },
+        ),
+        Err(ContractError::Std(_))
+    ));
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(sponsor.as_str(), &[coin(100)]),
+        ExecuteMsg::DepositFor {
+            job_id: job_id(1),
+            beneficiary: user.clone(),
+        },
+    )?;
+    assert!(res
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "beneficiary" && attr.value == user.as_str()));
+    let deposits = |deps: Deps, address: &Addr| -> Result<Vec<JobInfo>> {
+        Ok(query::<DepositInfoResponse>(
+            deps,
+            mock_env(),
+            QueryMsg::GetDepositInfo {
+                address: address.clone(),
+                start_after: None,
+                limit: None,
+            },
+        )?
+        .deposits)
+    };
+    assert_eq!(
+        deposits(deps.as_ref(), &user)?,
+        vec![JobInfo {
+            coin: coin(100),
+            job_id: job_id(1),
+        }]
+    );
+    assert!(deposits(deps.as_ref(), &sponsor)?.is_empty());
+
+    // The beneficiary holds the withdrawal rights, not the sponsor.
+    let withdraw = ExecuteMsg::Withdraw {
+        withdraw_info: vec![JobInfo {
+            coin: coin(100),
+            job_id: job_id(1),
+        }],
+    };
+    assert!(matches!(
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(sponsor.as_str(), &[]),
+            withdraw.clone(),
+        ),
+        Err(ContractError::InsufficientBalance { .. })
+    ));
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(user.as_str(), &[]),
+        withdraw,
+    )?;
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: user.to_string(),
+            amount: vec![coin(100)],
+        })
+    );
+    Ok(())
+}
+
+#[test]
+fn move_and_transfer_balance() -> Result<()> {
+    let (mut deps, ..) = setup()?;
+    let addr_a = deps.api.addr_validate("aaa")?;
+    let addr_b = deps.api.addr_validate("bbb")?;
+    let _ = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(addr_a.as_str(), &[coin(100)]),
+        ExecuteMsg::Deposit { job_id: job_id(1) },
+    )?;
+    let deposits = |deps: Deps, address: &Addr| -> Result<Vec<JobInfo>> {
+        Ok(query::<DepositInfoResponse>(
+            deps,
+            mock_env(),
+            QueryMsg::GetDepositInfo {
+                address: address.clone(),
+                start_after: None,
+                limit: None,
+            },
+        )?
+        .deposits)
+    };
+    let job_funds = |deps: Deps, id: i32| -> Result<Vec<Coin>> {
+        Ok(query::<JobInfoResponse>(
+            deps,
+            mock_env(),
+            QueryMsg::GetJobInfo { job_id: job_id(id) },
+        )?
+        .funds)
+    };
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(addr_a.as_str(), &[]),
+        ExecuteMsg::MoveBalance {
+            from_job: job_id(1),
+            to_job: job_id(2),
+            coin: coin(60),
+        },
+    )?;
+    // Nothing leaves the contract.
+    assert!(res.messages.is_empty());
+    assert_eq!(
+        deposits(deps.as_ref(), &addr_a)?,
+        vec![
+            JobInfo {
+                coin: coin(40),
+                job_id: job_id(1),
+            },
+            JobInfo {
+                coin: coin(60),
+                job_id: job_id(2),
+            },
+        ]
+    );
+    assert_eq!(job_funds(deps.as_ref(), 2)?, vec![coin(60)]);
+
+    let _ = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(addr_a.as_str(), &[]),
+        ExecuteMsg::TransferBalance {
+            to: addr_b.clone(),
+            job_id: job_id(1),
+            coin: coin(40),
+        },
+    )?;
+    assert_eq!(
+        deposits(deps.as_ref(), &addr_a)?,
+        vec![JobInfo {
+            coin: coin(60),
+            job_id: job_id(2),
+        }]
+    );
+    assert_eq!(
+        deposits(deps.as_ref(), &addr_b)?,
+        vec![JobInfo {
+            coin: coin(40),
+            job_id: job_id(1),
+        }]
+    );
+    assert_eq!(job_funds(deps.as_ref(), 1)?, vec![coin(40)]);
+
+    let mut execute_as_a = |msg| {
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(addr_a.as_str(), &[]),
+            msg,
+        )
+    };
+    assert!(matches!(
+        execute_as_a(ExecuteMsg::MoveBalance {
+            from_job: job_id(2),
+            to_job: job_id(1),
+            coin: coin(61),
+        }),
+        Err(ContractError::InsufficientBalance { .. })
+    ));
+    assert!(matches!(
+        execute_as_a(ExecuteMsg::TransferBalance {
+            to: addr_b,
+            job_id: job_id(1),
+            coin: coin(1),
+        }),
+        Err(ContractError::InsufficientBalance { .. })
+    ));
+    assert!(matches!(
+        execute_as_a(ExecuteMsg::MoveBalance {
+            from_job: job_id(2),
+            to_job: job_id(1),
+            coin: coin(0),
+        }),
+        Err(ContractError::EmptyTransfer {})
+    ));
+    assert!(matches!(
+        execute_as_a(ExecuteMsg::TransferBalance {
+            to: Addr::unchecked("Bbb"),
+            job_id: job_id(2),
+            coin: coin(1),
+        }),
+        Err(ContractError::Std(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn deposit_withdraw_errors() -> Result<()> {
+    let (mut deps, ..) = setup()?;
+
+    let addr_a = deps.api.addr_validate("aaa")?;
+    for (deposit, job_id) in [
+        // No empty deposits.
+        (mock_info(addr_a.as_str(), &[]), job_id(1)),
+        // Or zero deposits.
+        (mock_info(addr_a.as_str(), &[coin(0)]), job_id(2)),
+    ] {
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                deposit,
+                ExecuteMsg::Deposit { job_id },
+            ),
+            Err(ContractError::EmptyDeposit {})
+        ));
+    }
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(addr_a.as_str(), &[coin(1000)]),
+        ExecuteMsg::Deposit { job_id: job_id(1) },
+    )?;
+
+    // Failed withdrawals report what is available.
+    match execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(addr_a.as_str(), &[]),
+        ExecuteMsg::Withdraw {
+            withdraw_info: vec![JobInfo {
+                coin: coin(2000),
+                job_id: job_id(1),
+            }],
+        },
+    ) {
+        Err(ContractError::InsufficientBalance {
+            job_id: id,
+            denom,
+            available,
+            requested,
+        }) => {
+            assert_eq!(id, job_id(1));
+            assert_eq!(denom, "¤");
+            assert_eq!(available, Uint128::new(1000));
+            assert_eq!(requested, Uint128::new(2000));
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    for withdraw_info in [
+        // No withdrawing nothing.
+        vec![],
+        // Or too much.
+        vec![JobInfo {
+            coin: coin(2000),
+            job_id: job_id(1),
+        }],
+        // Or too much but piecemeal.
+        vec![
+            JobInfo {
+                coin: coin(501),
+                job_id: job_id(1),
+            },
+            JobInfo {
+                coin: coin(501),
+                job_id: job_id(1),
+            },
+        ],
+        // Or of the wrong denomination.
+        vec![JobInfo {
+            coin: coin2(1),
+            job_id: job_id(1),
+        }],
+    ] {
+        assert!(execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(addr_a.as_str(), &[]),
+            ExecuteMsg::Withdraw { withdraw_info },
+        )
+        .is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn unexpected_funds() -> Result<()> {
+    let (mut deps, ..) = setup()?;
+    let addr_a = deps.api.addr_validate("aaa")?;
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(addr_a.as_str(), &[coin(100)]),
+        ExecuteMsg::Deposit { job_id: job_id(1) },
+    )?;
+
+    // Only deposits take funds; anything sent with another message is refused, rather
+    // than left in the contract with no balance to show for it.
+    for msg in [
+        ExecuteMsg::Withdraw {
+            withdraw_info: vec![JobInfo {
+                coin: coin(10),
+                job_id: job_id(1),
+            }],
+        },
+        ExecuteMsg::MoveBalance {
+            from_job: job_id(1),
+            to_job: job_id(2),
+            coin: coin(10),
+        },
+        ExecuteMsg::TransferBalance {
+            to: Addr::unchecked("bbb"),
+            job_id: job_id(1),
+            coin: coin(10),
+        },
+        ExecuteMsg::ClaimRewards {},
+        ExecuteMsg::WithConsensus {
+            message_id: "1".to_string(),
+            payload: Binary::default(),
+            signed_at: None,
+            proof: None,
+            signatures: vec![],
+        },
+        ExecuteMsg::WithConsensusBatch { messages: vec![] },
+    ] {
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(addr_a.as_str(), &[coin(5)]),
+                msg,
+            ),
+            Err(ContractError::UnexpectedFunds {})
+        ));
+    }
+
+    // Sent with nothing, the same withdrawal goes through.
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(addr_a.as_str(), &[]),
+        ExecuteMsg::Withdraw {
+            withdraw_info: vec![JobInfo {
+                coin: coin(10),
+                job_id: job_id(1),
+            }],
+        },
+    )?;
+    Ok(())
+}
+
+#[test]
+fn simple_validation() -> Result<()> {
+    let mut deps = mock_dependencies_with_balances(&[]);
+
+    let mut base_message_id: u64 = 0xBA5EBA11 - 1;
+    // Generate a unique message id.
+    let mut mid = || -> String {
+        base_message_id += 1;
+        format!("{:x}", base_message_id)
+    };
+
+    let addresses: Vec<_> = [
+        "aaa", "bbb", "ccc", "ddd", "eee", "fff", "ggg", "hhh", "iii", "jjj",
+    ]
+    .into_iter()
+    .map(|addr| Ok(deps.api.addr_validate(addr)?))
+    .collect::<Result<_>>()?;
+    let non_validator = deps.api.addr_validate("eve")?;
+    let keys: Vec<_> = addresses.iter().map(|_| gen_keys()).collect();
+
+    let _ = instantiate(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("creator", &[]),
+        InstantiateMsg {
+            valset: addresses
+                .iter()
+                .zip(&keys)
+                .map(|(addr, (_, pubkey))| Validator {
+                    public_key: pubkey.clone(),
+                    stake: Uint128::new(10),
+                    address: vec![addr.clone()],
+                })
+                .collect(),
+            threshold: None,
+            denoms: None,
+        },
+    )?;
+
+    let t = |deps: DepsMut,
+             addr: &Addr,
+             message_id: &str,
+             keys: &[(SecretKey, PubKey)]|
+     -> Result<()> {
+        let valid_json = br#"{"stub":{}}"#;
+        execute(
+            deps,
+            mock_env(),
+            mock_info(addr.as_str(), &[]),
+            ExecuteMsg::WithConsensus {
+                message_id: message_id.to_string(),
+                payload: Binary::from(valid_json),
+                signed_at: None,
+                proof: None,
+                signatures: sign(keys, message_id, valid_json, None),
+            },
+        )?;
+        Ok(())
+    };
+
+    // No messages validate with less than quorum. Exactly half is not quorum.
+    for addr in [&addresses[0], &non_validator] {
+        for n in 0..=5 {
+            assert!(t(deps.as_mut(), addr, &mid(), &keys[..n]).is_err());
+        }
+    }
+    // Non trusted addreses never work.
+    for n in 0..=keys.len() {
+        assert!(t(deps.as_mut(), &non_validator, &mid(), &keys[..n]).is_err());
+    }
+    // Trusted addreses do, if they have enough signatures.
+    for addr in &addresses {
+        for n in 6..10 {
+            t(deps.as_mut(), addr, &mid(), &keys[..n])?;
+        }
+    }
+    // But not if you try to reuse an id!
+    t(deps.as_mut(), &addresses[0], "😠", &keys)?;
+    assert!(t(deps.as_mut(), &addresses[0], "😠", &keys).is_err());
+
+    // And if you change the valset...
+    let new_addr = deps.api.addr_validate("new_hotness")?;
+    let (privkey, pubkey) = gen_keys();
+    let update_json = validation::canonical_payload(&ConsensusMsg::UpdateValset {
+        valset: vec![Validator {
+            public_key: pubkey.clone(),
+            stake: Uint128::new(100),
+            address: vec![new_addr.clone()],
+        }],
+    })?;
+    let message_id = mid();
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(addresses[0].as_str(), &[]),
+        ExecuteMsg::WithConsensus {
+            message_id: message_id.clone(),
+            payload: Binary::from(update_json.as_slice()),
+            signed_at: None,
+            proof: None,
+            signatures: sign(&keys, &message_id, &update_json, None),
+        },
+    )?;
+
+    // Then they can sign new messages
+    t(deps.as_mut(), &new_addr, &mid(), &[(privkey, pubkey)])?;
+    // But others can't.
+    assert!(t(deps.as_mut(), &addresses[0], &mid(), &keys).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn pausing() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+    let depositor = deps.api.addr_validate("aaa")?;
+
+    let deposit = |deps: DepsMut| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(depositor.as_str(), &[coin(10)]),
+            ExecuteMsg::Deposit { job_id: job_id(1) },
+        )
+    };
+    let withdraw = |deps: DepsMut| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(depositor.as_str(), &[]),
+            ExecuteMsg::Withdraw {
+                withdraw_info: vec![JobInfo {
+                    coin: coin(1),
+                    job_id: job_id(1),
+                }],
+            },
+        )
+    };
+    let move_balance = |deps: DepsMut| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(depositor.as_str(), &[]),
+            ExecuteMsg::MoveBalance {
+                from_job: job_id(1),
+                to_job: job_id(2),
+                coin: coin(1),
+            },
+        )
+    };
+    let set_paused = |deps: DepsMut, message_id: &str, paused: bool| {
+        with_consensus(
+            deps,
+            &relayer,
+            &keys,
+            message_id,
+            &ConsensusMsg::SetPaused {
+                deposits: paused,
+                withdrawals: paused,
+                jobs: paused,
+            },
+        )
+    };
+    let stub = |deps: DepsMut, message_id: &str| {
+        with_consensus(deps, &relayer, &keys, message_id, &ConsensusMsg::Stub {})
+    };
+
+    deposit(deps.as_mut())?;
+    set_paused(deps.as_mut(), "1", true)?;
+    assert!(deposit(deps.as_mut()).is_err());
+    assert!(withdraw(deps.as_mut()).is_err());
+    assert!(move_balance(deps.as_mut()).is_err());
+    assert!(stub(deps.as_mut(), "2").is_err());
+
+    // Consensus can still unpause.
+    set_paused(deps.as_mut(), "3", false)?;
+    deposit(deps.as_mut())?;
+    withdraw(deps.as_mut())?;
+    move_balance(deps.as_mut())?;
+    stub(deps.as_mut(), "4")?;
+
+    Ok(())
+}
+
+#[test]
+fn sudo_recovery() -> Result<()> {
+    let (mut deps, relayer, lost_keys) = setup()?;
+    let keys = vec![gen_keys()];
+
+    // Governance pauses deposits while the valset is unusable...
+    sudo(
+        deps.as_mut(),
+        mock_env(),
+        SudoMsg::SetPaused {
+            deposits: true,
+            withdrawals: false,
+            jobs: false,
+        },
+    )?;
+    assert!(matches!(
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("aaa", &[coin(10)]),
+            ExecuteMsg::Deposit { job_id: job_id(1) },
+        ),
+        Err(ContractError::Paused { .. })
+    ));
+
+    // ...and installs a new one, which consensus then answers to.
+    sudo(
+        deps.as_mut(),
+        mock_env(),
+        SudoMsg::UpdateValset {
+            valset: vec![Validator {
+                public_key: keys[0].1.clone(),
+                stake: Uint128::new(1),
+                address: vec![relayer.clone()],
+            }],
+        },
+    )?;
+    let unpause = ConsensusMsg::SetPaused {
+        deposits: false,
+        withdrawals: false,
+        jobs: false,
+    };
+    assert!(with_consensus(deps.as_mut(), &relayer, &lost_keys, "1", &unpause).is_err());
+    with_consensus(deps.as_mut(), &relayer, &keys, "2", &unpause)?;
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("aaa", &[coin(10)]),
+        ExecuteMsg::Deposit { job_id: job_id(1) },
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn config() -> Result<()> {
+    let mut deps = mock_dependencies_with_balances(&[]);
+    let relayer = deps.api.addr_validate("relayer")?;
+    let keys: Vec<_> = (0..3).map(|_| gen_keys()).collect();
+    // The first validator relays for the valset.
+    let valset: Vec<_> = keys
+        .iter()
+        .enumerate()
+        .map(|(i, (_, pubkey))| Validator {
+            public_key: pubkey.clone(),
+            stake: Uint128::new(1),
+            address: if i == 0 {
+                vec![relayer.clone()]
+            } else {
+                vec![]
+            },
+        })
+        .collect();
+
+    // Half the stake or less could sign conflicting messages, and all of it can't be
+    // required.
+    for threshold in [Decimal::percent(49), Decimal::one()] {
+        assert!(matches!(
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("creator", &[]),
+                InstantiateMsg {
+                    valset: valset.clone(),
+                    threshold: Some(threshold),
+                    denoms: None,
+                },
+            ),
+            Err(ContractError::InvalidThreshold { .. })
+        ));
+    }
+    let threshold = Decimal::percent(67);
+    let _ = instantiate(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("creator", &[]),
+        InstantiateMsg {
+            valset,
+            threshold: Some(threshold),
+            denoms: Some(vec![coin(0).denom]),
+        },
+    )?;
+    assert_eq!(
+        query::<Config>(deps.as_ref(), mock_env(), QueryMsg::GetConfig {})?,
+        Config {
+            threshold,
+            paused: Paused::default(),
+            denoms: Some(vec![coin(0).denom]),
+            relayer_fee: None,
+            valset_id: 1,
+            relay: RelayConfig::default(),
+        }
+    );
+
+    // Two of three falls short.
+    let stub = ConsensusMsg::Stub {};
+    assert!(matches!(
+        with_consensus(deps.as_mut(), &relayer, &keys[..2], "1", &stub)
+            .unwrap_err()
+            .downcast::<ContractError>()?,
+        ContractError::ThresholdNotMet { signed, required }
+            if signed == Uint128::new(2) && required == Uint128::new(3)
+    ));
+    with_consensus(deps.as_mut(), &relayer, &keys, "2", &stub)?;
+
+    // Only allowed denominations may be deposited.
+    let deposit = |deps: DepsMut, funds: &[Coin]| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info("aaa", funds),
+            ExecuteMsg::Deposit { job_id: job_id(1) },
+        )
+    };
+    deposit(deps.as_mut(), &[coin(10)])?;
+    assert!(matches!(
+        deposit(deps.as_mut(), &[coin2(10)]),
+        Err(ContractError::DenomNotAllowed { denom }) if denom == coin2(0).denom
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn relayer_rotation() -> Result<()> {
+    let mut deps = mock_dependencies_with_balances(&[]);
+    let relayer_a = deps.api.addr_validate("relayer_a")?;
+    let relayer_b = deps.api.addr_validate("relayer_b")?;
+    let hot_key = deps.api.addr_validate("hot_key")?;
+    let keys = vec![gen_keys(), gen_keys()];
+    let _ = instantiate(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("creator", &[]),
+        InstantiateMsg {
+            valset: keys
+                .iter()
+                .zip([&relayer_a, &relayer_b])
+                .map(|((_, pubkey), addr)| Validator {
+                    public_key: pubkey.clone(),
+                    stake: Uint128::new(1),
+                    address: vec![addr.clone()],
+                })
+                .collect(),
+            threshold: None,
+            denoms: None,
+        },
+    )?;
+    let pubkey_a = keys[0].1.clone();
+
+    // Swap validator A's relayer for a new hot key.
+    with_consensus(
+        deps.as_mut(),
+        &relayer_b,
+        &keys,
+        "1",
+        &ConsensusMsg::AddRelayer {
+            public_key: pubkey_a.clone(),
+            address: hot_key.clone(),
+        },
+    )?;
+    with_consensus(
+        deps.as_mut(),
+        &relayer_b,
+        &keys,
+        "2",
+        &ConsensusMsg::RemoveRelayer {
+            public_key: pubkey_a.clone(),
+            address: relayer_a.clone(),
+        },
+    )?;
+    assert!(with_consensus(
+        deps.as_mut(),
+        &relayer_a,
+        &keys,
+        "3",
+        &ConsensusMsg::Stub {}
+    )
+    .is_err());
+    // Submissions are attributed to the validator the relayer acts for.
+    let res = with_consensus(deps.as_mut(), &hot_key, &keys, "4", &ConsensusMsg::Stub {})?;
+    assert!(res
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "validator" && attr.value == pubkey_a.0.to_base64()));
+
+    for (message_id, msg) in [
+        // An address relays for only one validator.
+        (
+            "5",
+            ConsensusMsg::AddRelayer {
+                public_key: keys[1].1.clone(),
+                address: hot_key.clone(),
+            },
+        ),
+        // And only for validators in the valset.
+        (
+            "6",
+            ConsensusMsg::AddRelayer {
+                public_key: gen_keys().1,
+                address: relayer_a.clone(),
+            },
+        ),
+        // Nor for addresses that aren't valid.
+        (
+            "6b",
+            ConsensusMsg::AddRelayer {
+                public_key: keys[1].1.clone(),
+                address: Addr::unchecked("HOT_KEY"),
+            },
+        ),
+        // Addresses are only removed from their own validator.
+        (
+            "7",
+            ConsensusMsg::RemoveRelayer {
+                public_key: keys[1].1.clone(),
+                address: hot_key.clone(),
+            },
+        ),
+    ] {
+        assert!(with_consensus(deps.as_mut(), &relayer_b, &keys, message_id, &msg).is_err());
+    }
+
+    // Nor can a new valset give one address to two validators.
+    let valset = keys
+        .iter()
+        .map(|(_, pubkey)| Validator {
+            public_key: pubkey.clone(),
+            stake: Uint128::new(1),
+            address: vec![relayer_b.clone()],
+        })
+        .collect();
+    assert!(matches!(
+        with_consensus(
+            deps.as_mut(),
+            &relayer_b,
+            &keys,
+            "8",
+            &ConsensusMsg::UpdateValset { valset }
+        )
+        .unwrap_err()
+        .downcast::<ContractError>()?,
+        ContractError::DuplicateRelayer { address } if address == relayer_b
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn migrate_trusted_addresses() -> Result<()> {
+    let mut deps = mock_dependencies_with_balances(&[]);
+    let relayer_a = deps.api.addr_validate("relayer_a")?;
+    let relayer_b = deps.api.addr_validate("relayer_b")?;
+    let keys = vec![gen_keys(), gen_keys()];
+    let _ = instantiate(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("creator", &[]),
+        InstantiateMsg {
+            valset: keys
+                .iter()
+                .map(|(_, pubkey)| Validator {
+                    public_key: pubkey.clone(),
+                    stake: Uint128::new(1),
+                    address: vec![],
+                })
+                .collect(),
+            threshold: None,
+            denoms: None,
+        },
+    )?;
+    // Trusted addresses as the earliest versions stored them, in a bare list.
+    LEGACY_TRUSTED_ADDRESSES.save(
+        &mut deps.storage,
+        &vec![relayer_a.clone(), relayer_b.clone()],
+    )?;
+
+    // With more than one validator, which one each address relays for must be given.
+    assert!(matches!(
+        migrate(deps.as_mut(), mock_env(), MigrateMsg::default()),
+        Err(ContractError::UnmappedRelayer { address }) if address == relayer_a
+    ));
+    let relayers = [(&relayer_a, &keys[0].1), (&relayer_b, &keys[1].1)];
+    migrate(
+        deps.as_mut(),
+        mock_env(),
+        MigrateMsg {
+            relayers: relayers
+                .iter()
+                .map(|(address, public_key)| Relayer {
+                    public_key: (*public_key).clone(),
+                    address: (*address).clone(),
+                })
+                .collect(),
+        },
+    )?;
+    assert!(LEGACY_TRUSTED_ADDRESSES.may_load(&deps.storage)?.is_none());
+    for (address, public_key) in relayers {
+        let res = with_consensus(
+            deps.as_mut(),
+            address,
+            &keys,
+            address.as_str(),
+            &ConsensusMsg::Stub {},
+        )?;
+        assert!(res
+            .attributes
+            .iter()
+            .any(|attr| attr.key == "validator" && attr.value == public_key.0.to_base64()));
     }
-    // Trusted addreses do, if they have enough signatures.
-    for addr in &addresses {
-        for n in 6..10 {
-            t(deps.as_mut(), addr, &mid(), &keys[..n])?;
+
+    Ok(())
+}
+
+#[test]
+fn relayer_fees() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+    let target = deps.api.addr_validate("target")?;
+    let addr_a = deps.api.addr_validate("aaa")?;
+    let addr_b = deps.api.addr_validate("bbb")?;
+    for deposit in [
+        mock_info(addr_a.as_str(), &[coin(3)]),
+        mock_info(addr_b.as_str(), &[coin(10), coin2(1)]),
+    ] {
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            deposit,
+            ExecuteMsg::Deposit { job_id: job_id(1) },
+        )?;
+    }
+    with_consensus(
+        deps.as_mut(),
+        &relayer,
+        &keys,
+        "fee",
+        &ConsensusMsg::SetRelayerFee { fee: Some(coin(5)) },
+    )?;
+
+    let execute_job = ConsensusMsg::ExecuteJob {
+        job_id: job_id(1),
+        contract: target.clone(),
+        msg: Binary::from(br#"{"rebalance":{}}"#),
+    };
+    let res = with_consensus(deps.as_mut(), &relayer, &keys, "1", &execute_job)?;
+    assert_eq!(
+        res.messages.iter().map(|sub| &sub.msg).collect::<Vec<_>>(),
+        vec![&CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: target.to_string(),
+            msg: Binary::from(br#"{"rebalance":{}}"#),
+            funds: vec![],
+        })]
+    );
+    // The fee is shared pro rata, leaving the depositors 8 * 3/13 and 8 * 10/13 rounded
+    // down, while the unit this leaves over stays in the job's funds.
+    let deposits = |deps: Deps, address: &Addr| {
+        query::<DepositInfoResponse>(
+            deps,
+            mock_env(),
+            QueryMsg::GetDepositInfo {
+                address: address.clone(),
+                start_after: None,
+                limit: None,
+            },
+        )
+        .map(|res| res.deposits)
+    };
+    assert_eq!(
+        deposits(deps.as_ref(), &addr_a)?,
+        vec![JobInfo {
+            coin: coin(1),
+            job_id: job_id(1),
+        }]
+    );
+    assert_eq!(
+        deposits(deps.as_ref(), &addr_b)?,
+        vec![
+            JobInfo {
+                coin: coin(6),
+                job_id: job_id(1),
+            },
+            JobInfo {
+                coin: coin2(1),
+                job_id: job_id(1),
+            },
+        ]
+    );
+    let funds = |deps: Deps| {
+        query::<JobInfoResponse>(deps, mock_env(), QueryMsg::GetJobInfo { job_id: job_id(1) })
+            .map(|res| res.funds)
+    };
+    assert_eq!(funds(deps.as_ref())?, vec![coin(8), coin2(1)]);
+    with_consensus(deps.as_mut(), &relayer, &keys, "2", &execute_job)?;
+    // Until the job can no longer pay.
+    assert!(with_consensus(deps.as_mut(), &relayer, &keys, "3", &execute_job).is_err());
+
+    // The units left over go to the depositors that remain. Of the 3 left, the first
+    // depositor holds none and the second 2, until the second leaves.
+    assert_eq!(funds(deps.as_ref())?, vec![coin(3), coin2(1)]);
+    assert!(deposits(deps.as_ref(), &addr_a)?.is_empty());
+    let withdraw = |deps: DepsMut, address: &Addr, coin: Coin| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(address.as_str(), &[]),
+            ExecuteMsg::Withdraw {
+                withdraw_info: vec![JobInfo {
+                    coin,
+                    job_id: job_id(1),
+                }],
+            },
+        )
+    };
+    withdraw(deps.as_mut(), &addr_b, coin(2))?;
+    withdraw(deps.as_mut(), &addr_a, coin(1))?;
+    assert_eq!(funds(deps.as_ref())?, vec![coin2(1)]);
+    assert_eq!(
+        query::<RelayerRewardsResponse>(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::GetRelayerRewards {
+                address: relayer.clone()
+            }
+        )?,
+        RelayerRewardsResponse {
+            address: relayer.clone(),
+            rewards: vec![coin(10)],
         }
+    );
+
+    let claim = |deps: DepsMut| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(relayer.as_str(), &[]),
+            ExecuteMsg::ClaimRewards {},
+        )
+    };
+    let res = claim(deps.as_mut())?;
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: relayer.to_string(),
+            amount: vec![coin(10)],
+        })
+    );
+    // Rewards are only paid out once.
+    assert!(matches!(
+        claim(deps.as_mut()),
+        Err(ContractError::NoRewards {})
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn fee_shares() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+    let deposit = |deps: DepsMut, address: &str, id: i32, amount: u128| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(address, &[coin(amount)]),
+            ExecuteMsg::Deposit { job_id: job_id(id) },
+        )
+    };
+    let mut message_id = 0;
+    let mut run_job = |mut deps: DepsMut, id: i32, fee: u128| -> Result<()> {
+        message_id += 2;
+        with_consensus(
+            deps.branch(),
+            &relayer,
+            &keys,
+            &(message_id - 1).to_string(),
+            &ConsensusMsg::SetRelayerFee {
+                fee: Some(coin(fee)),
+            },
+        )?;
+        with_consensus(
+            deps,
+            &relayer,
+            &keys,
+            &message_id.to_string(),
+            &ConsensusMsg::ExecuteJob {
+                job_id: job_id(id),
+                contract: Addr::unchecked("target"),
+                msg: Binary::from(b"{}"),
+            },
+        )?;
+        Ok(())
+    };
+    let depositors = |deps: Deps, id: i32| -> Result<Vec<(String, u128)>> {
+        let res: JobDepositorsResponse = query(
+            deps,
+            mock_env(),
+            QueryMsg::GetJobDepositors {
+                job_id: job_id(id),
+                start_after: None,
+                limit: None,
+            },
+        )?;
+        Ok(res
+            .depositors
+            .into_iter()
+            .map(|depositor| {
+                (
+                    depositor.address.into_string(),
+                    depositor.coin.amount.u128(),
+                )
+            })
+            .collect())
+    };
+
+    // Charging a job writes none of its deposits, however many there are.
+    for i in 0..20 {
+        deposit(deps.as_mut(), &format!("dust{i:02}"), 1, 1)?;
     }
-    // But not if you try to reuse an id!
-    t(deps.as_mut(), &addresses[0], "😠", &keys)?;
-    assert!(t(deps.as_mut(), &addresses[0], "😠", &keys).is_err());
+    deposit(deps.as_mut(), "aaa", 1, 980)?;
+    let deposits = |storage: &dyn Storage| {
+        balances()
+            .range(storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()
+    };
+    let before = deposits(&deps.storage)?;
+    run_job(deps.as_mut(), 1, 100)?;
+    assert_eq!(deposits(&deps.storage)?, before);
+    // Each dust deposit is left with less than a unit, and no longer shows.
+    assert_eq!(
+        depositors(deps.as_ref(), 1)?,
+        vec![("aaa".to_string(), 882)]
+    );
+
+    // Fees that leave little of a large deposit move the job to a later epoch, with a
+    // coarser share, while balances carry on as before.
+    deposit(deps.as_mut(), "bbb", 2, 1 << 40)?;
+    run_job(deps.as_mut(), 2, (1 << 40) - 1)?;
+    assert!(JOB_SHARES.load(&deps.storage, (&job_id(2), "¤"))?.epoch > 0);
+    deposit(deps.as_mut(), "ccc", 2, 5)?;
+    assert_eq!(
+        depositors(deps.as_ref(), 2)?,
+        vec![("bbb".to_string(), 1), ("ccc".to_string(), 5)]
+    );
+
+    // A fee of every unit leaves nothing to share, and the next deposit starts afresh.
+    run_job(deps.as_mut(), 2, 6)?;
+    assert!(depositors(deps.as_ref(), 2)?.is_empty());
+    assert_eq!(
+        query::<ListJobsResponse>(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::ListJobs {
+                start_after: None,
+                limit: None,
+            },
+        )?
+        .jobs
+        .len(),
+        1
+    );
+    deposit(deps.as_mut(), "ccc", 2, 4)?;
+    assert_eq!(depositors(deps.as_ref(), 2)?, vec![("ccc".to_string(), 4)]);
+
+    Ok(())
+}
+
+#[test]
+fn permissionless_relaying() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+    let outsider = deps.api.addr_validate("eve")?;
+    let height = mock_env().block.height;
+
+    // Submit a stub signed at `signed_at`, claiming it was signed at `claimed`.
+    let submit = |deps: DepsMut,
+                  addr: &Addr,
+                  message_id: &str,
+                  signed_at: Option<u64>,
+                  claimed: Option<u64>| {
+        let payload = br#"{"stub":{}}"#;
+        execute(
+            deps,
+            mock_env(),
+            mock_info(addr.as_str(), &[]),
+            ExecuteMsg::WithConsensus {
+                message_id: message_id.to_string(),
+                payload: Binary::from(payload),
+                signed_at: claimed,
+                proof: None,
+                signatures: sign(&keys, message_id, payload, signed_at),
+            },
+        )
+    };
+    let set_relay_config = |deps: DepsMut, message_id: &str, priority_blocks: u64| {
+        with_consensus(
+            deps,
+            &relayer,
+            &keys,
+            message_id,
+            &ConsensusMsg::SetRelayConfig {
+                permissionless: true,
+                priority_blocks,
+            },
+        )
+    };
+
+    assert!(matches!(
+        submit(deps.as_mut(), &outsider, "1", None, None),
+        Err(ContractError::UntrustedRelayer { relayer }) if relayer == outsider
+    ));
+    set_relay_config(deps.as_mut(), "2", 0)?;
+    assert_eq!(
+        query::<Config>(deps.as_ref(), mock_env(), QueryMsg::GetConfig {})?.relay,
+        RelayConfig {
+            permissionless: true,
+            priority_blocks: 0,
+        }
+    );
+    let res = submit(deps.as_mut(), &outsider, "3", None, None)?;
+    assert!(!res.attributes.iter().any(|attr| attr.key == "validator"));
+
+    set_relay_config(deps.as_mut(), "4", 10)?;
+    // Untrusted relayers must wait out the priority window.
+    assert!(matches!(
+        submit(deps.as_mut(), &outsider, "5", None, None),
+        Err(ContractError::MissingSigningHeight {})
+    ));
+    assert!(matches!(
+        submit(deps.as_mut(), &outsider, "5", Some(height), Some(height)),
+        Err(ContractError::PriorityWindow { until }) if until == height + 10
+    ));
+    // Trusted relayers don't.
+    submit(deps.as_mut(), &relayer, "6", Some(height), Some(height))?;
+    // And the signing height can't be forged.
+    assert!(submit(
+        deps.as_mut(),
+        &outsider,
+        "7",
+        Some(height),
+        Some(height - 10)
+    )
+    .is_err());
+    submit(
+        deps.as_mut(),
+        &outsider,
+        "7",
+        Some(height - 10),
+        Some(height - 10),
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn consensus_batch() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+    let new_keys = vec![gen_keys()];
+
+    fn signed(
+        keys: &[(SecretKey, PubKey)],
+        message_id: &str,
+        msg: &ConsensusMsg,
+        continue_on_error: bool,
+    ) -> Result<SignedMessage> {
+        let payload = validation::canonical_payload(msg)?;
+        Ok(SignedMessage {
+            message_id: message_id.to_string(),
+            signatures: sign(keys, message_id, &payload, None),
+            payload: Binary::from(payload),
+            signed_at: None,
+            proof: None,
+            continue_on_error,
+        })
+    }
+    let batch = |deps: DepsMut, messages: Vec<SignedMessage>| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(relayer.as_str(), &[]),
+            ExecuteMsg::WithConsensusBatch { messages },
+        )
+    };
+    let stub = ConsensusMsg::Stub {};
+
+    // Messages that may fail are skipped, and the rest applied.
+    let res = batch(
+        deps.as_mut(),
+        vec![
+            signed(&keys, "1", &stub, false)?,
+            signed(&new_keys, "2", &stub, true)?,
+            signed(&keys, "3", &stub, false)?,
+        ],
+    )?;
+    let attr = |key: &str| -> Vec<String> {
+        res.attributes
+            .iter()
+            .filter(|attr| attr.key == key)
+            .map(|attr| attr.value.clone())
+            .collect()
+    };
+    assert_eq!(attr("message_id"), vec!["1", "3"]);
+    assert_eq!(attr("skipped_message_id"), vec!["2"]);
+    // Otherwise the whole batch fails.
+    assert!(batch(
+        deps.as_mut(),
+        vec![
+            signed(&keys, "4", &stub, false)?,
+            signed(&new_keys, "5", &stub, false)?,
+        ],
+    )
+    .is_err());
+    // And previously applied messages can't be replayed.
+    assert!(batch(deps.as_mut(), vec![signed(&keys, "1", &stub, false)?]).is_err());
+
+    // A valset update applies to the messages after it.
+    let update_valset = ConsensusMsg::UpdateValset {
+        valset: vec![Validator {
+            public_key: new_keys[0].1.clone(),
+            stake: Uint128::new(1),
+            address: vec![relayer.clone()],
+        }],
+    };
+    batch(
+        deps.as_mut(),
+        vec![
+            signed(&keys, "2", &update_valset, false)?,
+            signed(&new_keys, "6", &stub, false)?,
+        ],
+    )?;
+    assert!(batch(deps.as_mut(), vec![signed(&keys, "7", &stub, false)?]).is_err());
+
+    // A skipped message leaves nothing behind, not even what it wrote before failing:
+    // this valset would have removed the relayer before finding its duplicate address.
+    let outsider = deps.api.addr_validate("outsider")?;
+    let conflicting_valset = ConsensusMsg::UpdateValset {
+        valset: vec![Validator {
+            public_key: gen_keys().1,
+            stake: Uint128::new(1),
+            address: vec![outsider.clone(), outsider],
+        }],
+    };
+    let res = batch(
+        deps.as_mut(),
+        vec![
+            signed(&new_keys, "8", &conflicting_valset, true)?,
+            signed(&new_keys, "9", &stub, false)?,
+        ],
+    )?;
+    assert!(res
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "skipped_message_id" && attr.value == "8"));
+    assert!(res
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "message_id" && attr.value == "9"));
+
+    Ok(())
+}
+
+#[test]
+fn staged_writes() {
+    let mut base = MockStorage::new();
+    for key in [b"a", b"c", b"e"] {
+        base.set(key, b"base");
+    }
+    let mut transaction = Transaction::new(&base);
+    transaction.set(b"b", b"staged");
+    transaction.remove(b"c");
+    transaction.set(b"e", b"staged");
+    transaction.set(b"f", b"staged");
+    transaction.remove(b"g");
+
+    let range = |storage: &dyn Storage, start: Option<&[u8]>, end: Option<&[u8]>, order| {
+        storage
+            .range(start, end, order)
+            .map(|(key, value)| {
+                (
+                    String::from_utf8(key).unwrap(),
+                    String::from_utf8(value).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let records = |records: &[(&str, &str)]| {
+        records
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+    };
+    // Reads see the staged writes over the base storage, in either order.
+    assert_eq!(transaction.get(b"c"), None);
+    assert_eq!(
+        range(&transaction, None, None, Order::Ascending),
+        records(&[
+            ("a", "base"),
+            ("b", "staged"),
+            ("e", "staged"),
+            ("f", "staged")
+        ])
+    );
+    assert_eq!(
+        range(&transaction, Some(b"b"), Some(b"f"), Order::Descending),
+        records(&[("e", "staged"), ("b", "staged")])
+    );
+    assert!(range(&transaction, Some(b"f"), Some(b"b"), Order::Ascending).is_empty());
+    // While the base storage is untouched until they are committed.
+    assert_eq!(base.get(b"b"), None);
+    transaction.into_writes().commit(&mut base);
+    assert_eq!(
+        range(&base, None, None, Order::Ascending),
+        records(&[
+            ("a", "base"),
+            ("b", "staged"),
+            ("e", "staged"),
+            ("f", "staged")
+        ])
+    );
+}
+
+#[test]
+fn skipped_jobs() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+    let addr_a = deps.api.addr_validate("aaa")?;
+    let addr_b = deps.api.addr_validate("bbb")?;
+    for deposit in [
+        mock_info(addr_a.as_str(), &[coin(3)]),
+        mock_info(addr_b.as_str(), &[coin(10)]),
+    ] {
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            deposit,
+            ExecuteMsg::Deposit { job_id: job_id(1) },
+        )?;
+    }
+    with_consensus(
+        deps.as_mut(),
+        &relayer,
+        &keys,
+        "fee",
+        &ConsensusMsg::SetRelayerFee { fee: Some(coin(5)) },
+    )?;
+    with_consensus(
+        deps.as_mut(),
+        &relayer,
+        &keys,
+        "schedule",
+        &ConsensusMsg::SetJobSchedule {
+            job_id: job_id(1),
+            schedule: Some(Schedule {
+                interval: Interval::Blocks(10),
+                start: 0,
+                end: None,
+            }),
+        },
+    )?;
+
+    let execute_job = validation::canonical_payload(&ConsensusMsg::ExecuteJob {
+        job_id: job_id(1),
+        contract: Addr::unchecked("target"),
+        msg: Binary::from(b"{}"),
+    })?;
+    let batch = |deps: DepsMut, message_id: &str, payload: &[u8]| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(relayer.as_str(), &[]),
+            ExecuteMsg::WithConsensusBatch {
+                messages: vec![SignedMessage {
+                    message_id: message_id.to_string(),
+                    signatures: sign(&keys, message_id, payload, None),
+                    payload: Binary::from(payload),
+                    signed_at: None,
+                    proof: None,
+                    continue_on_error: true,
+                }],
+            },
+        )
+    };
+    let failed = |deps: DepsMut, id: u64| {
+        reply(
+            deps,
+            mock_env(),
+            Reply {
+                id,
+                result: SubMsgResult::Err("target failed".to_string()),
+            },
+        )
+    };
+    let job_state = |deps: Deps| -> Result<_> {
+        let funds =
+            query::<JobInfoResponse>(deps, mock_env(), QueryMsg::GetJobInfo { job_id: job_id(1) })?
+                .funds;
+        let rewards = query::<RelayerRewardsResponse>(
+            deps,
+            mock_env(),
+            QueryMsg::GetRelayerRewards {
+                address: relayer.clone(),
+            },
+        )?
+        .rewards;
+        let due = query::<DueJobsResponse>(
+            deps,
+            mock_env(),
+            QueryMsg::DueJobs {
+                start_after: None,
+                limit: None,
+            },
+        )?
+        .jobs
+        .len();
+        Ok((funds, rewards, due))
+    };
+
+    // A job that may be skipped is replied to should its target fail.
+    let res = batch(deps.as_mut(), "1", &execute_job)?;
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(res.messages[0].reply_on, ReplyOn::Error);
+    assert_eq!(job_state(deps.as_ref())?, (vec![coin(8)], vec![coin(5)], 0));
+
+    // The failure undoes the fee, the window and the message id.
+    let res = failed(deps.as_mut(), res.messages[0].id)?;
+    assert!(res
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "skipped_message_id" && attr.value == "1"));
+    assert_eq!(job_state(deps.as_ref())?, (vec![coin(13)], vec![], 1));
+    let res: JobDepositorsResponse = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::GetJobDepositors {
+            job_id: job_id(1),
+            start_after: None,
+            limit: None,
+        },
+    )?;
+    assert_eq!(
+        res.depositors,
+        vec![
+            DepositorInfo {
+                address: addr_a.clone(),
+                coin: coin(3),
+            },
+            DepositorInfo {
+                address: addr_b.clone(),
+                coin: coin(10),
+            },
+        ]
+    );
 
-    // And if you change the valset...
-    let new_addr = deps.api.addr_validate("new_hotness")?;
-    let (privkey, pubkey) = gen_keys();
-    let update_json = serde_json::to_string(&ConsensusMsg::UpdateValset {
+    // So the message may be relayed again.
+    let res = batch(deps.as_mut(), "1", &execute_job)?;
+    assert_eq!(job_state(deps.as_ref())?, (vec![coin(8)], vec![coin(5)], 0));
+    // Once the job has run, the next batch clears what it would have undone.
+    batch(
+        deps.as_mut(),
+        "2",
+        &validation::canonical_payload(&ConsensusMsg::Stub {})?,
+    )?;
+    assert!(matches!(
+        failed(deps.as_mut(), res.messages[0].id).unwrap_err(),
+        ContractError::UnknownReply { .. }
+    ));
+    assert_eq!(job_state(deps.as_ref())?, (vec![coin(8)], vec![coin(5)], 0));
+
+    Ok(())
+}
+
+#[test]
+fn merkle_proofs() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+
+    let stub = validation::canonical_payload(&ConsensusMsg::Stub {})?;
+    let update_valset = validation::canonical_payload(&ConsensusMsg::UpdateValset {
         valset: vec![Validator {
-            public_key: pubkey.clone(),
-            stake: Uint128::new(100),
-            address: vec![new_addr.clone()],
+            public_key: gen_keys().1,
+            stake: Uint128::new(1),
+            address: vec![relayer.clone()],
         }],
     })?;
-    let message_id = mid();
-    execute(
+    let messages = [
+        ("1", &stub),
+        ("2", &stub),
+        ("3", &update_valset),
+        ("4", &stub),
+    ];
+    let leaves: Vec<_> = messages
+        .iter()
+        .map(|(message_id, payload)| {
+            validation::merkle_leaf(&validation::hash(message_id, payload, None))
+        })
+        .collect();
+    let left = validation::merkle_parent(&leaves[0], &leaves[1]);
+    let right = validation::merkle_parent(&leaves[2], &leaves[3]);
+    let root = Binary::from(validation::merkle_parent(&left, &right));
+    let proofs = [
+        vec![&leaves[1], &right],
+        vec![&leaves[0], &right],
+        vec![&leaves[3], &left],
+        vec![&leaves[2], &left],
+    ];
+    let root_signatures = signer::sign_root(&secret_keys(&keys), &root);
+
+    let submit = |deps: DepsMut, i: usize, siblings: &[&Vec<u8>], signatures: &[Signature]| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(relayer.as_str(), &[]),
+            ExecuteMsg::WithConsensus {
+                message_id: messages[i].0.to_string(),
+                payload: Binary::from(messages[i].1.as_slice()),
+                signed_at: None,
+                proof: Some(MerkleProof {
+                    root: root.clone(),
+                    siblings: siblings
+                        .iter()
+                        .map(|sibling| Binary::from(sibling.as_slice()))
+                        .collect(),
+                }),
+                signatures: signatures.to_vec(),
+            },
+        )
+    };
+
+    // A root needs signatures the first time.
+    assert!(submit(deps.as_mut(), 0, &proofs[0], &[]).is_err());
+    submit(deps.as_mut(), 0, &proofs[0], &root_signatures)?;
+    // But not after that.
+    submit(deps.as_mut(), 1, &proofs[1], &[])?;
+    // Proofs must lead to the root.
+    assert!(submit(deps.as_mut(), 3, &proofs[1], &[]).is_err());
+    assert!(submit(deps.as_mut(), 3, &[&leaves[2]], &[]).is_err());
+    // And simulations agree, though the root is verified.
+    let simulation: ConsensusSimulation = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::SimulateConsensus {
+            message_id: messages[3].0.to_string(),
+            payload: Binary::from(messages[3].1.as_slice()),
+            signed_at: None,
+            proof: Some(MerkleProof {
+                root: root.clone(),
+                siblings: proofs[1]
+                    .iter()
+                    .map(|sibling| Binary::from(sibling.as_slice()))
+                    .collect(),
+            }),
+            signatures: vec![],
+            relayer: None,
+        },
+    )?;
+    assert_eq!(simulation.proof_valid, Some(false));
+    assert!(simulation.root_verified);
+    assert!(!simulation.threshold_reached);
+    // Changing the valset forgets every verified root.
+    submit(deps.as_mut(), 2, &proofs[2], &[])?;
+    assert!(submit(deps.as_mut(), 3, &proofs[3], &[]).is_err());
+    assert!(submit(deps.as_mut(), 3, &proofs[3], &root_signatures).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn canonical_payloads() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+
+    let submit = |deps: DepsMut, message_id: &str, payload: &[u8], signatures| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(relayer.as_str(), &[]),
+            ExecuteMsg::WithConsensus {
+                message_id: message_id.to_string(),
+                payload: Binary::from(payload),
+                signed_at: None,
+                proof: None,
+                signatures,
+            },
+        )
+    };
+
+    let msg = ConsensusMsg::SetPaused {
+        deposits: true,
+        withdrawals: false,
+        jobs: false,
+    };
+    let signing_bytes: SigningBytes = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::GetSigningBytes {
+            message_id: "1".to_string(),
+            msg,
+            signed_at: None,
+        },
+    )?;
+    assert_eq!(
+        signing_bytes.payload.as_slice(),
+        br#"{"set_paused":{"deposits":true,"jobs":false,"withdrawals":false}}"#
+    );
+    submit(
+        deps.as_mut(),
+        "1",
+        &signing_bytes.payload,
+        sign_hash(&keys, &signing_bytes.hash)?,
+    )?;
+    // Keys are sorted at every depth.
+    assert_eq!(
+        validation::canonical_payload(&ConsensusMsg::SetJobSchedule {
+            job_id: job_id(1),
+            schedule: Some(Schedule {
+                start: 0,
+                interval: Interval::Blocks(10),
+                end: None,
+            }),
+        })?,
+        br#"{"set_job_schedule":{"job_id":"1","schedule":{"end":null,"interval":{"blocks":10},"start":0}}}"#
+    );
+
+    // The same message in any other encoding is rejected, even when signed.
+    for payload in [
+        &br#"{"set_paused": {"deposits": true, "jobs": false, "withdrawals": false}}"#[..],
+        &br#"{"set_paused":{"deposits":true,"withdrawals":false,"jobs":false}}"#[..],
+    ] {
+        let signatures = sign(&keys, "2", payload, None);
+        assert!(submit(deps.as_mut(), "2", payload, signatures).is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn signing_hash_layout() {
+    // Each field is preceded by its length as a big-endian u64.
+    let field = |bytes: &[u8]| [&(bytes.len() as u64).to_be_bytes()[..], bytes].concat();
+    let preimage = [
+        field(b"7"),
+        field(br#"{"stub":{}}"#),
+        field(&9u64.to_be_bytes()),
+    ]
+    .concat();
+    assert_eq!(
+        validation::hash("7", br#"{"stub":{}}"#, Some(9)),
+        Sha256::digest(preimage).to_vec()
+    );
+    let preimage = [field(b"7"), field(br#"{"stub":{}}"#), field(b"")].concat();
+    assert_eq!(
+        validation::hash("7", br#"{"stub":{}}"#, None),
+        Sha256::digest(preimage).to_vec()
+    );
+    // So bytes can't shift from one field to the next.
+    assert_ne!(
+        validation::hash("12", b"3", None),
+        validation::hash("1", b"23", None)
+    );
+}
+
+/// A mock [`Api`] that counts the signatures it verifies.
+#[derive(Default)]
+struct CountingApi {
+    api: MockApi,
+    verified: Cell<usize>,
+}
+
+impl Api for CountingApi {
+    fn addr_validate(&self, human: &str) -> StdResult<Addr> {
+        self.api.addr_validate(human)
+    }
+
+    fn addr_canonicalize(&self, human: &str) -> StdResult<CanonicalAddr> {
+        self.api.addr_canonicalize(human)
+    }
+
+    fn addr_humanize(&self, canonical: &CanonicalAddr) -> StdResult<Addr> {
+        self.api.addr_humanize(canonical)
+    }
+
+    fn secp256k1_verify(
+        &self,
+        message_hash: &[u8],
+        signature: &[u8],
+        public_key: &[u8],
+    ) -> Result<bool, VerificationError> {
+        self.verified.set(self.verified.get() + 1);
+        self.api
+            .secp256k1_verify(message_hash, signature, public_key)
+    }
+
+    fn secp256k1_recover_pubkey(
+        &self,
+        message_hash: &[u8],
+        signature: &[u8],
+        recovery_param: u8,
+    ) -> Result<Vec<u8>, RecoverPubkeyError> {
+        self.api
+            .secp256k1_recover_pubkey(message_hash, signature, recovery_param)
+    }
+
+    fn ed25519_verify(
+        &self,
+        message: &[u8],
+        signature: &[u8],
+        public_key: &[u8],
+    ) -> Result<bool, VerificationError> {
+        self.api.ed25519_verify(message, signature, public_key)
+    }
+
+    fn ed25519_batch_verify(
+        &self,
+        messages: &[&[u8]],
+        signatures: &[&[u8]],
+        public_keys: &[&[u8]],
+    ) -> Result<bool, VerificationError> {
+        self.api
+            .ed25519_batch_verify(messages, signatures, public_keys)
+    }
+
+    fn debug(&self, message: &str) {
+        self.api.debug(message)
+    }
+}
+
+#[test]
+fn simulate_consensus() -> Result<()> {
+    let mut deps = mock_dependencies_with_balances(&[]);
+    let relayer = deps.api.addr_validate("relayer")?;
+    let outsider = deps.api.addr_validate("eve")?;
+    let keys: Vec<_> = (0..4).map(|_| gen_keys()).collect();
+    let _ = instantiate(
         deps.as_mut(),
         mock_env(),
-        mock_info(&addresses[0].as_str(), &[]),
-        ExecuteMsg::WithConsensus {
-            message_id: message_id.clone(),
-            raw_json: update_json.to_string(),
-            signatures: sign(&keys, &message_id, &update_json.to_string())?,
+        mock_info("creator", &[]),
+        InstantiateMsg {
+            valset: keys
+                .iter()
+                .enumerate()
+                .map(|(i, (_, pubkey))| Validator {
+                    public_key: pubkey.clone(),
+                    stake: Uint128::new(10),
+                    address: if i == 0 {
+                        vec![relayer.clone()]
+                    } else {
+                        vec![]
+                    },
+                })
+                .collect(),
+            threshold: None,
+            denoms: None,
         },
     )?;
 
-    // Then they can sign new messages
-    t(deps.as_mut(), &new_addr, &mid(), &[(privkey, pubkey)])?;
-    // But others can't.
-    assert!(t(deps.as_mut(), &addresses[0], &mid(), &keys).is_err());
+    let payload = validation::canonical_payload(&ConsensusMsg::Stub {})?;
+    let stranger = vec![gen_keys()];
+    let signatures = [
+        sign(&keys[..1], "1", &payload, None),
+        // The same validator twice.
+        sign(&keys[..1], "1", &payload, None),
+        sign(&keys[1..2], "1", &payload, None),
+        // Someone outside the valset.
+        sign(&stranger, "1", &payload, None),
+        // A signature over some other message.
+        sign(&keys[2..3], "2", &payload, None),
+        // Which doesn't hide a valid one from the same validator.
+        sign(&keys[3..], "2", &payload, None),
+        sign(&keys[3..], "1", &payload, None),
+    ]
+    .concat();
+    let simulate = |deps: Deps, payload: &[u8], relayer: &Addr| -> Result<ConsensusSimulation> {
+        query(
+            deps,
+            mock_env(),
+            QueryMsg::SimulateConsensus {
+                message_id: "1".to_string(),
+                payload: Binary::from(payload),
+                signed_at: None,
+                proof: None,
+                signatures: signatures.clone(),
+                relayer: Some(relayer.clone()),
+            },
+        )
+    };
+
+    let simulation = simulate(deps.as_ref(), &payload, &relayer)?;
+    let check = |pubkey: &PubKey, known, valid, duplicate, stake| SignatureCheck {
+        pubkey: pubkey.clone(),
+        known,
+        valid,
+        duplicate,
+        stake: Uint128::new(stake),
+    };
+    assert_eq!(
+        simulation,
+        ConsensusSimulation {
+            message_id_used: false,
+            payload_error: None,
+            relayer_error: None,
+            proof_valid: None,
+            root_verified: false,
+            signatures: vec![
+                check(&keys[0].1, true, true, false, 10),
+                check(&keys[0].1, true, true, true, 10),
+                check(&keys[1].1, true, true, false, 10),
+                check(&stranger[0].1, false, true, false, 0),
+                check(&keys[2].1, true, false, false, 10),
+                check(&keys[3].1, true, false, false, 10),
+                check(&keys[3].1, true, true, false, 10),
+            ],
+            signed_stake: Uint128::new(30),
+            total_stake: Uint128::new(40),
+            threshold_reached: true,
+        }
+    );
+    // Which is what execution finds too, though it verifies only the signatures that may
+    // count, and stops once they make consensus.
+    let api = CountingApi::default();
+    let submit = |deps: DepsMut, message_id: &str, signatures: &[Signature]| {
+        execute(
+            DepsMut {
+                storage: deps.storage,
+                api: &api,
+                querier: deps.querier,
+            },
+            mock_env(),
+            mock_info(relayer.as_str(), &[]),
+            ExecuteMsg::WithConsensus {
+                message_id: message_id.to_string(),
+                payload: Binary::from(payload.as_slice()),
+                signed_at: None,
+                proof: None,
+                signatures: signatures.to_vec(),
+            },
+        )
+    };
+    submit(deps.as_mut(), "1", &signatures)?;
+    assert_eq!(api.verified.take(), 5);
+    let signatures = [
+        sign(&stranger, "3", &payload, None),
+        sign(&keys, "3", &payload, None),
+    ]
+    .concat();
+    submit(deps.as_mut(), "3", &signatures)?;
+    assert_eq!(api.verified.take(), 3);
+
+    let simulation = simulate(deps.as_ref(), br#"{"stub": {}}"#, &outsider)?;
+    assert!(simulation.payload_error.is_some());
+    assert!(simulation.relayer_error.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn scheduled_jobs() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+    let at_height = |height: u64| {
+        let mut env = mock_env();
+        env.block.height = height;
+        env
+    };
+    let mut message_id = 0;
+    let mut consensus_at = |deps: DepsMut, height: u64, msg: &ConsensusMsg| {
+        message_id += 1;
+        let msg = signer::with_consensus(&secret_keys(&keys), &message_id.to_string(), msg, None)?;
+        execute(
+            deps,
+            at_height(height),
+            mock_info(relayer.as_str(), &[]),
+            msg,
+        )
+        .map_err(eyre::Report::from)
+    };
+    let execute_job = |id: i32| ConsensusMsg::ExecuteJob {
+        job_id: job_id(id),
+        contract: Addr::unchecked("target"),
+        msg: Binary::from(b"{}"),
+    };
+    let due_jobs = |deps: Deps, height: u64| -> Result<Vec<DueJob>> {
+        Ok(query::<DueJobsResponse>(
+            deps,
+            at_height(height),
+            QueryMsg::DueJobs {
+                start_after: None,
+                limit: None,
+            },
+        )?
+        .jobs)
+    };
+
+    let schedule = Schedule {
+        interval: Interval::Blocks(10),
+        start: 100,
+        end: Some(130),
+    };
+    for invalid in [
+        Schedule {
+            interval: Interval::Seconds(0),
+            ..schedule.clone()
+        },
+        Schedule {
+            end: Some(100),
+            ..schedule.clone()
+        },
+    ] {
+        assert!(matches!(
+            consensus_at(
+                deps.as_mut(),
+                1,
+                &ConsensusMsg::SetJobSchedule {
+                    job_id: job_id(1),
+                    schedule: Some(invalid),
+                },
+            )
+            .unwrap_err()
+            .downcast::<ContractError>()?,
+            ContractError::InvalidSchedule {}
+        ));
+    }
+    consensus_at(
+        deps.as_mut(),
+        1,
+        &ConsensusMsg::SetJobSchedule {
+            job_id: job_id(1),
+            schedule: Some(schedule.clone()),
+        },
+    )?;
+
+    assert!(matches!(
+        consensus_at(deps.as_mut(), 95, &execute_job(1))
+            .unwrap_err()
+            .downcast::<ContractError>()?,
+        ContractError::JobNotDue {
+            next_window: 100,
+            ..
+        }
+    ));
+    assert_eq!(
+        due_jobs(deps.as_ref(), 105)?,
+        vec![DueJob {
+            job_id: job_id(1),
+            schedule: schedule.clone(),
+            window: 100,
+        }]
+    );
+    let res = consensus_at(deps.as_mut(), 105, &execute_job(1))?;
+    assert!(res
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "window" && attr.value == "100"));
+
+    // Once per window.
+    assert!(due_jobs(deps.as_ref(), 109)?.is_empty());
+    assert!(matches!(
+        consensus_at(deps.as_mut(), 109, &execute_job(1))
+            .unwrap_err()
+            .downcast::<ContractError>()?,
+        ContractError::JobNotDue {
+            next_window: 110,
+            ..
+        }
+    ));
+    consensus_at(deps.as_mut(), 119, &execute_job(1))?;
+    // A skipped window doesn't carry over.
+    consensus_at(deps.as_mut(), 125, &execute_job(1))?;
+    assert!(matches!(
+        consensus_at(deps.as_mut(), 126, &execute_job(1))
+            .unwrap_err()
+            .downcast::<ContractError>()?,
+        ContractError::ScheduleEnded { .. }
+    ));
+    assert!(matches!(
+        consensus_at(deps.as_mut(), 130, &execute_job(1))
+            .unwrap_err()
+            .downcast::<ContractError>()?,
+        ContractError::ScheduleEnded { .. }
+    ));
+
+    // Unscheduled jobs run at any time, and so do jobs whose schedule is lifted.
+    consensus_at(deps.as_mut(), 130, &execute_job(2))?;
+    consensus_at(deps.as_mut(), 130, &execute_job(2))?;
+    consensus_at(
+        deps.as_mut(),
+        130,
+        &ConsensusMsg::SetJobSchedule {
+            job_id: job_id(1),
+            schedule: None,
+        },
+    )?;
+    consensus_at(deps.as_mut(), 131, &execute_job(1))?;
+    assert!(due_jobs(deps.as_ref(), 131)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn underfunded_scheduled_jobs() -> Result<()> {
+    let (mut deps, relayer, keys) = setup()?;
+    with_consensus(
+        deps.as_mut(),
+        &relayer,
+        &keys,
+        "fee",
+        &ConsensusMsg::SetRelayerFee { fee: Some(coin(5)) },
+    )?;
+    let height = mock_env().block.height;
+    for (id, start) in [(1, 0), (2, height + 1), (3, 0)] {
+        with_consensus(
+            deps.as_mut(),
+            &relayer,
+            &keys,
+            &format!("schedule {id}"),
+            &ConsensusMsg::SetJobSchedule {
+                job_id: job_id(id),
+                schedule: Some(Schedule {
+                    interval: Interval::Blocks(10),
+                    start,
+                    end: None,
+                }),
+            },
+        )?;
+    }
+    let deposit = |deps: DepsMut, id, amount| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info("aaa", &[coin(amount)]),
+            ExecuteMsg::Deposit { job_id: job_id(id) },
+        )
+    };
+    deposit(deps.as_mut(), 1, 3)?;
+    deposit(deps.as_mut(), 3, 5)?;
+
+    let due_jobs = |deps: Deps, start_after: Option<i32>, limit| -> Result<_> {
+        let res: DueJobsResponse = query(
+            deps,
+            mock_env(),
+            QueryMsg::DueJobs {
+                start_after: start_after.map(job_id),
+                limit: Some(limit),
+            },
+        )?;
+        Ok((
+            res.jobs
+                .into_iter()
+                .map(|job| job.job_id)
+                .collect::<Vec<_>>(),
+            res.next_cursor,
+        ))
+    };
+    // Pages are bounded by the schedules scanned, including those not due, and those
+    // that can't pay the fee.
+    assert_eq!(due_jobs(deps.as_ref(), None, 2)?, (vec![], Some(job_id(2))));
+    assert_eq!(
+        due_jobs(deps.as_ref(), Some(2), 2)?,
+        (vec![job_id(3)], None)
+    );
+    assert_eq!(
+        due_jobs(deps.as_ref(), Some(1), 0)?,
+        (vec![], Some(job_id(2)))
+    );
+
+    let execute_job = validation::canonical_payload(&ConsensusMsg::ExecuteJob {
+        job_id: job_id(1),
+        contract: Addr::unchecked("target"),
+        msg: Binary::from(b"{}"),
+    })?;
+    let batch = |deps: DepsMut, message_id: &str| {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(relayer.as_str(), &[]),
+            ExecuteMsg::WithConsensusBatch {
+                messages: vec![SignedMessage {
+                    message_id: message_id.to_string(),
+                    signatures: sign(&keys, message_id, &execute_job, None),
+                    payload: Binary::from(execute_job.as_slice()),
+                    signed_at: None,
+                    proof: None,
+                    continue_on_error: true,
+                }],
+            },
+        )
+    };
+
+    // A job that can't pay its fee is skipped, and its window stays open.
+    let res = batch(deps.as_mut(), "1")?;
+    assert!(res.messages.is_empty());
+    assert!(res
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "skipped_message_id" && attr.value == "1"));
+
+    // So it is listed, and runs in that window, once funded.
+    deposit(deps.as_mut(), 1, 2)?;
+    assert_eq!(due_jobs(deps.as_ref(), None, 1)?.0, vec![job_id(1)]);
+    let res = batch(deps.as_mut(), "1")?;
+    assert_eq!(res.messages.len(), 1);
+    assert!(due_jobs(deps.as_ref(), None, 1)?.0.is_empty());
+
+    // Nothing is due while jobs are paused.
+    with_consensus(
+        deps.as_mut(),
+        &relayer,
+        &keys,
+        "pause",
+        &ConsensusMsg::SetPaused {
+            deposits: false,
+            withdrawals: false,
+            jobs: true,
+        },
+    )?;
+    assert_eq!(due_jobs(deps.as_ref(), Some(2), 2)?, (vec![], None));
+
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+enum Op {
+    Deposit {
+        user: usize,
+        job: i32,
+        coins: Vec<(bool, u128)>,
+    },
+    Withdraw {
+        user: usize,
+        job: i32,
+        denom: bool,
+        amount: u128,
+    },
+    MoveBalance {
+        user: usize,
+        from_job: i32,
+        to_job: i32,
+        denom: bool,
+        amount: u128,
+    },
+    TransferBalance {
+        user: usize,
+        to: usize,
+        job: i32,
+        denom: bool,
+        amount: u128,
+    },
+    ExecuteJob {
+        job: i32,
+    },
+    SetRelayerFee {
+        fee: Option<(bool, u128)>,
+    },
+    ClaimRewards,
+    UpdateValset,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        // Deposits may repeat a denomination or include zero coins.
+        (
+            0..3usize,
+            0..3,
+            proptest::collection::vec((any::<bool>(), 0..50u128), 1..4)
+        )
+            .prop_map(|(user, job, coins)| Op::Deposit { user, job, coins }),
+        (0..3usize, 0..3, any::<bool>(), 1..80u128).prop_map(|(user, job, denom, amount)| {
+            Op::Withdraw {
+                user,
+                job,
+                denom,
+                amount,
+            }
+        }),
+        (0..3usize, 0..3, 0..3, any::<bool>(), 0..80u128).prop_map(
+            |(user, from_job, to_job, denom, amount)| Op::MoveBalance {
+                user,
+                from_job,
+                to_job,
+                denom,
+                amount,
+            }
+        ),
+        (0..3usize, 0..3usize, 0..3, any::<bool>(), 0..80u128).prop_map(
+            |(user, to, job, denom, amount)| Op::TransferBalance {
+                user,
+                to,
+                job,
+                denom,
+                amount,
+            }
+        ),
+        (0..3).prop_map(|job| Op::ExecuteJob { job }),
+        proptest::option::of((any::<bool>(), 1..30u128)).prop_map(|fee| Op::SetRelayerFee { fee }),
+        Just(Op::ClaimRewards),
+        Just(Op::UpdateValset),
+    ]
+}
+
+/// Every key and value in `storage`.
+fn snapshot(storage: &dyn Storage) -> Vec<(Vec<u8>, Vec<u8>)> {
+    storage.range(None, None, Order::Ascending).collect()
+}
 
+/// Check that the balance maps agree with one another, and with `bank`, the funds the
+/// contract holds by denomination.
+fn check_invariants(storage: &dyn Storage, bank: &BTreeMap<String, Uint128>) -> Result<()> {
+    let mut deposits = BTreeMap::new();
+    for item in balances().range(storage, None, None, Order::Ascending) {
+        let ((address, job_id, denom), deposit) = item?;
+        assert!(!deposit.shares.is_zero(), "empty deposit persists");
+        deposits.insert((job_id.0, address, denom), deposit);
+    }
+    let index = balances()
+        .idx
+        .job_id
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| {
+            let ((address, job_id, denom), deposit) = item?;
+            Ok(((job_id.0, address, denom), deposit))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        index,
+        deposits.clone().into_iter().collect::<Vec<_>>(),
+        "index differs from balances"
+    );
+
+    // Depositors never hold more than the funds, nor more shares than were issued.
+    let mut job_totals = BTreeMap::new();
+    for item in JOB_TOTALS.range(storage, None, None, Order::Ascending) {
+        let ((job_id, denom), amount) = item?;
+        assert!(!amount.is_zero(), "zero total persists");
+        job_totals.insert((job_id.0, denom), amount);
+    }
+    let mut held_shares = BTreeMap::new();
+    let mut balances = BTreeMap::new();
+    for ((job_id, _, denom), deposit) in &deposits {
+        let pool = Pool::load(storage, &JobId(job_id.clone()), denom)?;
+        assert!(
+            deposit.epoch <= pool.issued.epoch,
+            "deposit from a later epoch"
+        );
+        *held_shares
+            .entry((job_id.clone(), denom.clone()))
+            .or_insert_with(Uint256::zero) += deposit
+            .shares
+            .checked_shr(32 * (pool.issued.epoch - deposit.epoch))
+            .unwrap_or_default();
+        *balances
+            .entry((job_id.clone(), denom.clone()))
+            .or_insert_with(Uint128::zero) += pool.balance(deposit);
+    }
+    for item in JOB_SHARES.range(storage, None, None, Order::Ascending) {
+        let ((job_id, denom), issued) = item?;
+        let key = (job_id.0, denom);
+        assert!(
+            held_shares.get(&key).copied().unwrap_or_default() <= issued.shares,
+            "deposits hold more shares than were issued"
+        );
+        assert!(
+            balances.get(&key).copied().unwrap_or_default()
+                <= job_totals.get(&key).copied().unwrap_or_default(),
+            "balances exceed the job's funds"
+        );
+    }
+
+    // Funds are either deposited, or earned by a relayer and not yet claimed.
+    let mut held = BTreeMap::new();
+    for ((_, denom), amount) in &job_totals {
+        *held.entry(denom.clone()).or_insert_with(Uint128::zero) += *amount;
+    }
+    for item in RELAYER_REWARDS.range(storage, None, None, Order::Ascending) {
+        let ((_, denom), amount) = item?;
+        assert!(!amount.is_zero(), "zero reward persists");
+        *held.entry(denom).or_insert_with(Uint128::zero) += amount;
+    }
+    let bank: BTreeMap<_, _> = bank
+        .iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(denom, amount)| (denom.clone(), *amount))
+        .collect();
+    assert_eq!(held, bank, "bank balance differs from deposits and rewards");
+    Ok(())
+}
+
+fn run_ops(ops: Vec<Op>) -> Result<()> {
+    let (mut deps, relayer, mut keys) = setup()?;
+    let users = ["aaa", "bbb", "ccc"].map(Addr::unchecked);
+    let denom = |second: bool| {
+        if second {
+            coin2(0).denom
+        } else {
+            coin(0).denom
+        }
+    };
+
+    let mut bank = BTreeMap::new();
+    for (i, op) in ops.into_iter().enumerate() {
+        let message_id = i.to_string();
+        let mut funds = vec![];
+        let mut new_keys = None;
+        // The chain reverts failed transactions, which mock storage doesn't.
+        let backup = snapshot(&deps.storage);
+        let res = match op {
+            Op::Deposit { user, job, coins } => {
+                funds = coins
+                    .into_iter()
+                    .map(|(second, amount)| cosmwasm_std::coin(amount, denom(second)))
+                    .collect();
+                execute(
+                    deps.as_mut(),
+                    mock_env(),
+                    mock_info(users[user].as_str(), &funds),
+                    ExecuteMsg::Deposit {
+                        job_id: job_id(job),
+                    },
+                )
+                .map_err(eyre::Report::from)
+            }
+            Op::Withdraw {
+                user,
+                job,
+                denom: second,
+                amount,
+            } => execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(users[user].as_str(), &[]),
+                ExecuteMsg::Withdraw {
+                    withdraw_info: vec![JobInfo {
+                        coin: cosmwasm_std::coin(amount, denom(second)),
+                        job_id: job_id(job),
+                    }],
+                },
+            )
+            .map_err(eyre::Report::from),
+            Op::MoveBalance {
+                user,
+                from_job,
+                to_job,
+                denom: second,
+                amount,
+            } => execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(users[user].as_str(), &[]),
+                ExecuteMsg::MoveBalance {
+                    from_job: job_id(from_job),
+                    to_job: job_id(to_job),
+                    coin: cosmwasm_std::coin(amount, denom(second)),
+                },
+            )
+            .map_err(eyre::Report::from),
+            Op::TransferBalance {
+                user,
+                to,
+                job,
+                denom: second,
+                amount,
+            } => execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(users[user].as_str(), &[]),
+                ExecuteMsg::TransferBalance {
+                    to: users[to].clone(),
+                    job_id: job_id(job),
+                    coin: cosmwasm_std::coin(amount, denom(second)),
+                },
+            )
+            .map_err(eyre::Report::from),
+            Op::ExecuteJob { job } => with_consensus(
+                deps.as_mut(),
+                &relayer,
+                &keys,
+                &message_id,
+                &ConsensusMsg::ExecuteJob {
+                    job_id: job_id(job),
+                    contract: Addr::unchecked("target"),
+                    msg: Binary::from(b"{}"),
+                },
+            ),
+            Op::SetRelayerFee { fee } => with_consensus(
+                deps.as_mut(),
+                &relayer,
+                &keys,
+                &message_id,
+                &ConsensusMsg::SetRelayerFee {
+                    fee: fee.map(|(second, amount)| cosmwasm_std::coin(amount, denom(second))),
+                },
+            ),
+            Op::ClaimRewards => execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(relayer.as_str(), &[]),
+                ExecuteMsg::ClaimRewards {},
+            )
+            .map_err(eyre::Report::from),
+            Op::UpdateValset => {
+                let next = vec![gen_keys()];
+                let res = with_consensus(
+                    deps.as_mut(),
+                    &relayer,
+                    &keys,
+                    &message_id,
+                    &ConsensusMsg::UpdateValset {
+                        valset: vec![Validator {
+                            public_key: next[0].1.clone(),
+                            stake: Uint128::new(1),
+                            address: vec![relayer.clone()],
+                        }],
+                    },
+                );
+                new_keys = Some(next);
+                res
+            }
+        };
+
+        match res {
+            Ok(res) => {
+                for coin in funds {
+                    *bank.entry(coin.denom).or_insert_with(Uint128::zero) += coin.amount;
+                }
+                for msg in res.messages {
+                    if let CosmosMsg::Bank(BankMsg::Send { amount, .. }) = msg.msg {
+                        for coin in amount {
+                            let held = bank.entry(coin.denom).or_insert_with(Uint128::zero);
+                            *held = held.checked_sub(coin.amount)?;
+                        }
+                    }
+                }
+                if let Some(new_keys) = new_keys {
+                    keys = new_keys;
+                }
+            }
+            Err(_) => {
+                deps.storage = MockStorage::new();
+                for (key, value) in backup {
+                    deps.storage.set(&key, &value);
+                }
+            }
+        }
+        check_invariants(&deps.storage, &bank)?;
+    }
     Ok(())
 }
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn balance_accounting(ops in proptest::collection::vec(op(), 1..40)) {
+        run_ops(ops).unwrap();
+    }
+}
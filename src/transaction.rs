@@ -0,0 +1,133 @@
+//! Staging writes to storage so they can be committed or dropped as one.
+use cosmwasm_std::{Order, Record, Storage};
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::ops::Bound;
+
+/// Storage that reads through to `base`, but holds every write back until it is
+/// committed with [`Writes::commit`]. Dropping it discards the writes.
+pub(crate) struct Transaction<'a> {
+    base: &'a dyn Storage,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// The writes staged in a [`Transaction`], with `None` for a removal.
+pub(crate) struct Writes(BTreeMap<Vec<u8>, Option<Vec<u8>>>);
+
+/// A staged write, as iterated from a [`Transaction`].
+type Write<'b> = (&'b Vec<u8>, &'b Option<Vec<u8>>);
+
+impl<'a> Transaction<'a> {
+    pub fn new(base: &'a dyn Storage) -> Self {
+        Transaction {
+            base,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn into_writes(self) -> Writes {
+        Writes(self.writes)
+    }
+}
+
+impl Writes {
+    pub fn commit(self, storage: &mut dyn Storage) {
+        for (key, value) in self.0 {
+            match value {
+                Some(value) => storage.set(&key, &value),
+                None => storage.remove(&key),
+            }
+        }
+    }
+}
+
+impl Storage for Transaction<'_> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.writes.get(key) {
+            Some(value) => value.clone(),
+            None => self.base.get(key),
+        }
+    }
+
+    fn range<'b>(
+        &'b self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'b> {
+        let base = self.base.range(start, end, order);
+        // Like the base storage, an inverted range is empty rather than an error.
+        let writes: Box<dyn Iterator<Item = Write>> = match (start, end) {
+            (Some(start), Some(end)) if start >= end => Box::new(std::iter::empty()),
+            _ => {
+                let bounds = (
+                    start.map_or(Bound::Unbounded, |start| Bound::Included(start.to_vec())),
+                    end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_vec())),
+                );
+                let writes = self.writes.range(bounds);
+                match order {
+                    Order::Ascending => Box::new(writes),
+                    Order::Descending => Box::new(writes.rev()),
+                }
+            }
+        };
+        Box::new(Merge {
+            base: base.peekable(),
+            writes: writes.peekable(),
+            order,
+        })
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+}
+
+/// The records of the base storage in a range, overlaid with the writes to it, both
+/// iterated in `order`.
+struct Merge<'b, B, W>
+where
+    B: Iterator<Item = Record>,
+    W: Iterator<Item = Write<'b>>,
+{
+    base: Peekable<B>,
+    writes: Peekable<W>,
+    order: Order,
+}
+
+impl<'b, B, W> Iterator for Merge<'b, B, W>
+where
+    B: Iterator<Item = Record>,
+    W: Iterator<Item = Write<'b>>,
+{
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let written_first = match (self.base.peek(), self.writes.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (Some((base_key, _)), Some((written_key, _))) => {
+                    if base_key == *written_key {
+                        // The write shadows the record it replaces.
+                        self.base.next();
+                        true
+                    } else {
+                        (*written_key < base_key) == (self.order == Order::Ascending)
+                    }
+                }
+            };
+            if !written_first {
+                return self.base.next();
+            }
+            if let Some((key, Some(value))) = self.writes.next() {
+                return Some((key.clone(), value.clone()));
+            }
+        }
+    }
+}
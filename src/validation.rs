@@ -1,17 +1,30 @@
-///! Routines and storage associated with validating messages.
-use cosmwasm_std::{Addr, Binary, Deps, MessageInfo, Uint128};
+//! Routines and storage associated with validating messages.
+use crate::error::{ContractError, Result};
+use crate::msg::{ConsensusMsg, ConsensusSimulation, MerkleProof, SignatureCheck, SignedMessage};
+use crate::state::CONFIG;
+use cosmwasm_std::{Addr, Binary, Decimal, Deps, Env, MessageInfo, Uint128};
 use cw_storage_plus::{Item, Map};
-use eyre::{ensure, Result};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sha2::{Digest, Sha256};
 
 /// A list of public keys and their associated stake in our chain.
 pub const VALIDATORS: Item<Vec<ValKey>> = Item::new("validators");
 
-/// Addresses associated with our validators. Only these addresses may issue
-/// [`ExecuteMsg::WithConsensus`] messages.
-pub const TRUSTED_ADDRESSES: Item<Vec<Addr>> = Item::new("trusted_addreses");
+/// Addresses associated with our validators, mapped to the public key of the validator
+/// they relay for. Only these addresses may issue [`ExecuteMsg::WithConsensus`] messages.
+pub const TRUSTED_ADDRESSES: Map<&Addr, PubKey> = Map::new("trusted_addresses");
+
+/// The bare list of [`TRUSTED_ADDRESSES`] kept by the earliest versions, under its original
+/// misspelled key, left for [`crate::contract::migrate`] to translate.
+pub const LEGACY_TRUSTED_ADDRESSES: Item<Vec<Addr>> = Item::new("trusted_addreses");
+
+/// Merkle roots whose signatures have been verified, by the valset id they were verified
+/// against. Messages proven to be under a root need no signatures of their own while the
+/// valset is unchanged. A new valset gets a new id, so the roots of earlier ones never
+/// match again, and are left in place rather than cleared at a cost that grows with them.
+pub const SIGNED_ROOTS: Map<(u64, &[u8]), ()> = Map::new("signed_roots");
 
 /// Messages may not be replayed with the same `id`.
 pub const USED_MESSAGE_IDS: Map<&str, ()> = Map::new("used_message_ids");
@@ -25,84 +38,311 @@ pub struct ValKey {
     pub stake: Uint128,
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Signature {
     pub pubkey: PubKey,
     pub signature: Binary,
 }
 
-fn is_trusted(deps: Deps, address: &Addr) -> Result<bool> {
-    let trusted = TRUSTED_ADDRESSES.load(deps.storage)?;
-    Ok(trusted.binary_search(address).is_ok())
+/// Check that `sender` may relay a message signed at `signed_at`.
+fn check_relayer(deps: Deps, env: &Env, sender: &Addr, signed_at: Option<u64>) -> Result<()> {
+    if TRUSTED_ADDRESSES.has(deps.storage, sender) {
+        return Ok(());
+    }
+    let config = CONFIG.load(deps.storage)?.relay;
+    if !config.permissionless {
+        return Err(ContractError::UntrustedRelayer {
+            relayer: sender.clone(),
+        });
+    }
+    if config.priority_blocks > 0 {
+        let until = signed_at
+            .ok_or(ContractError::MissingSigningHeight {})?
+            .saturating_add(config.priority_blocks);
+        if env.block.height < until {
+            return Err(ContractError::PriorityWindow { until });
+        }
+    }
+    Ok(())
+}
+
+/// The canonical encoding of a consensus message, after the JSON Canonicalization Scheme
+/// (RFC 8785): JSON without whitespace, with the keys of every object in ascending order.
+/// Integers are written in full, where the scheme would round those beyond 2^53.
+pub(crate) fn canonical_payload<T: Serialize>(msg: &T) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&sort_keys(serde_json::to_value(msg)?))
 }
 
-/// Concatenate `raw_json` with `message_id`, used as a nonce and hash them for signing.
-pub(crate) fn hash(message_id: &str, raw_json: &str) -> Vec<u8> {
-    // TODO: Delimit these messages.
+/// `value` with the keys of every object in it sorted, whether or not `serde_json`
+/// preserves insertion order.
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, sort_keys(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+/// Concatenate `message_id`, used as a nonce, `payload` and the big-endian block height it
+/// was signed at, if given, each preceded by its length as a big-endian `u64`, and hash
+/// them for signing. An absent height is an empty field.
+pub(crate) fn hash(message_id: &str, payload: &[u8], signed_at: Option<u64>) -> Vec<u8> {
+    let signed_at = signed_at.map(u64::to_be_bytes);
+    let fields: [&[u8]; 3] = [
+        message_id.as_bytes(),
+        payload,
+        signed_at.as_ref().map_or(&[], |height| &height[..]),
+    ];
+    let mut hasher = Sha256::new();
+    for field in fields {
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// The hash validators sign to approve every message under a Merkle `root`.
+pub(crate) fn root_hash(root: &[u8]) -> Vec<u8> {
     Sha256::new()
-        .chain_update(raw_json.as_bytes())
-        .chain_update(message_id)
+        .chain_update(b"merkle_root")
+        .chain_update(root)
         .finalize()
         .to_vec()
 }
 
-fn is_signed<'a>(
+/// The leaf of a message in a Merkle tree of messages, from the hash it would be signed
+/// over alone. Leaves and parents are hashed with distinct prefixes, so neither can pass
+/// for the other.
+pub(crate) fn merkle_leaf(message_hash: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update([0])
+        .chain_update(message_hash)
+        .finalize()
+        .to_vec()
+}
+
+/// The parent of two nodes in a Merkle tree of messages.
+pub(crate) fn merkle_parent(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let (low, high) = if a <= b { (a, b) } else { (b, a) };
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(low)
+        .chain_update(high)
+        .finalize()
+        .to_vec()
+}
+
+fn is_proven(message_hash: &[u8], proof: &MerkleProof) -> bool {
+    let root = proof
+        .siblings
+        .iter()
+        .fold(merkle_leaf(message_hash), |node, sibling| {
+            merkle_parent(&node, sibling)
+        });
+    root == proof.root.as_slice()
+}
+
+/// Check that the message, or the Merkle root it is proven to be under, is signed.
+fn is_signed(deps: Deps, validators: &[ValKey], message: &SignedMessage) -> Result<()> {
+    let message_hash = hash(&message.message_id, &message.payload, message.signed_at);
+    match &message.proof {
+        None => is_hash_signed(deps, validators, &message_hash, &message.signatures),
+        Some(proof) => {
+            if !is_proven(&message_hash, proof) {
+                return Err(ContractError::InvalidMerkleProof {});
+            }
+            let valset_id = CONFIG.load(deps.storage)?.valset_id;
+            if SIGNED_ROOTS.has(deps.storage, (valset_id, &proof.root)) {
+                return Ok(());
+            }
+            is_hash_signed(
+                deps,
+                validators,
+                &root_hash(&proof.root),
+                &message.signatures,
+            )
+        }
+    }
+}
+
+fn is_hash_signed(
     deps: Deps,
-    message_id: &str,
-    raw_json: &'a str,
+    validators: &[ValKey],
+    message_hash: &[u8],
     signatures: &[Signature],
-) -> Result<bool> {
-    let message_hash = hash(message_id, raw_json);
-    let validators = VALIDATORS.load(deps.storage)?;
+) -> Result<()> {
     let total = validators.iter().map(|v| v.stake).sum::<Uint128>();
+    let threshold = CONFIG.load(deps.storage)?.threshold;
 
-    // We only care about the signatures for pubkeys among our validators. We can also
-    // sort larger stakes first and reach consensus faster.
+    // Only the signatures of validators count, so only those are verified, the largest
+    // stakes first to reach consensus with as few verifications as possible.
     let mut signatures: Vec<_> = signatures
         .iter()
-        .filter_map(|sig| {
-            match validators.binary_search_by(|probe| probe.pubkey.cmp(&sig.pubkey)) {
-                Ok(i) => Some((validators[i].stake, sig)),
-                Err(_) => None,
-            }
-        })
+        .filter_map(|sig| Some((stake_of(validators, &sig.pubkey)?, sig)))
         .collect();
-    // Sort big weights first.
-    signatures.sort_by(|(w1, _), (w2, _)| w2.cmp(w1));
-    let mut total_weight = Uint128::new(0);
-    for (weight, sig) in signatures {
-        if deps
+    signatures.sort_by(|(stake1, _), (stake2, _)| stake2.cmp(stake1));
+    let mut signed = Uint128::zero();
+    let mut counted: Vec<&PubKey> = Vec::new();
+    for (stake, sig) in signatures {
+        // Each validator counts once, so a signature of one already counted is skipped.
+        if counted.contains(&&sig.pubkey) {
+            continue;
+        }
+        let valid = deps
             .api
-            .secp256k1_verify(&message_hash, &sig.signature, &sig.pubkey.0)?
-        {
-            total_weight += weight;
-            if total_weight * Uint128::new(2) > total {
-                return Ok(true);
+            .secp256k1_verify(message_hash, &sig.signature, &sig.pubkey.0)
+            .unwrap_or(false);
+        if valid {
+            signed += stake;
+            counted.push(&sig.pubkey);
+            if reaches_threshold(signed, total, threshold) {
+                return Ok(());
             }
         }
     }
+    Err(ContractError::ThresholdNotMet {
+        signed,
+        required: total * threshold + Uint128::new(1),
+    })
+}
+
+/// Check every one of `signatures` over `message_hash` for a simulation, and sum the
+/// stake they carry. Only the signatures of validators that verify count, each validator
+/// once. Signatures that don't verify are dropped before duplicates, so they can't shadow
+/// a valid one, as when executing.
+fn check_signatures(
+    deps: Deps,
+    validators: &[ValKey],
+    message_hash: &[u8],
+    signatures: &[Signature],
+) -> (Uint128, Vec<SignatureCheck>) {
+    let mut signed = Uint128::zero();
+    let mut counted: Vec<&PubKey> = Vec::new();
+    let checks = signatures
+        .iter()
+        .map(|sig| {
+            let stake = stake_of(validators, &sig.pubkey);
+            let valid = deps
+                .api
+                .secp256k1_verify(message_hash, &sig.signature, &sig.pubkey.0)
+                .unwrap_or(false);
+            let duplicate = valid && counted.contains(&&sig.pubkey);
+            if let (Some(stake), true, false) = (stake, valid, duplicate) {
+                signed += stake;
+                counted.push(&sig.pubkey);
+            }
+            SignatureCheck {
+                pubkey: sig.pubkey.clone(),
+                known: stake.is_some(),
+                valid,
+                duplicate,
+                stake: stake.unwrap_or_default(),
+            }
+        })
+        .collect();
+    (signed, checks)
+}
+
+fn stake_of(validators: &[ValKey], pubkey: &PubKey) -> Option<Uint128> {
+    validators
+        .binary_search_by(|probe| probe.pubkey.cmp(pubkey))
+        .ok()
+        .map(|i| validators[i].stake)
+}
+
+/// Consensus requires strictly more than the `threshold` fraction of the total stake.
+fn reaches_threshold(signed: Uint128, total: Uint128, threshold: Decimal) -> bool {
+    signed > total * threshold
+}
+
+/// Run every check [`validate_payload`] would for `message`, without side effects, and
+/// report on each of them rather than stopping at the first failure.
+pub(crate) fn simulate(
+    deps: Deps,
+    env: &Env,
+    relayer: Option<&Addr>,
+    message: &SignedMessage,
+) -> Result<ConsensusSimulation> {
+    let validators = VALIDATORS.load(deps.storage)?;
+    let threshold = CONFIG.load(deps.storage)?.threshold;
+    let total_stake = validators.iter().map(|v| v.stake).sum::<Uint128>();
+    let message_hash = hash(&message.message_id, &message.payload, message.signed_at);
+    let (signed_hash, proof_valid, root_verified) = match &message.proof {
+        None => (message_hash, None, false),
+        Some(proof) => {
+            let valset_id = CONFIG.load(deps.storage)?.valset_id;
+            (
+                root_hash(&proof.root),
+                Some(is_proven(&message_hash, proof)),
+                SIGNED_ROOTS.has(deps.storage, (valset_id, &proof.root)),
+            )
+        }
+    };
+
+    let (signed_stake, signatures) =
+        check_signatures(deps, &validators, &signed_hash, &message.signatures);
 
-    Ok(false)
+    let payload_error = parse_payload::<ConsensusMsg>(&message.payload)
+        .err()
+        .map(|err| err.to_string());
+    let relayer_error = relayer.and_then(|relayer| {
+        check_relayer(deps, env, relayer, message.signed_at)
+            .err()
+            .map(|err| err.to_string())
+    });
+
+    Ok(ConsensusSimulation {
+        message_id_used: USED_MESSAGE_IDS.has(deps.storage, &message.message_id),
+        payload_error,
+        relayer_error,
+        proof_valid,
+        root_verified,
+        signatures,
+        signed_stake,
+        total_stake,
+        // A message that isn't under its root is rejected whatever the root's signatures.
+        threshold_reached: proof_valid != Some(false)
+            && (root_verified || reaches_threshold(signed_stake, total_stake, threshold)),
+    })
 }
 
-pub(crate) fn validate_json<'a, T>(
+pub(crate) fn validate_payload<'a, T>(
     deps: Deps,
+    env: &Env,
     info: &MessageInfo,
-    message_id: &str,
-    raw_json: &'a str,
-    signatures: &[Signature],
+    validators: &[ValKey],
+    message: &'a SignedMessage,
 ) -> Result<T>
 where
-    T: Deserialize<'a>,
+    T: Deserialize<'a> + Serialize,
+{
+    if USED_MESSAGE_IDS.has(deps.storage, &message.message_id) {
+        return Err(ContractError::MessageIdUsed {
+            message_id: message.message_id.clone(),
+        });
+    }
+    check_relayer(deps, env, &info.sender, message.signed_at)?;
+    is_signed(deps, validators, message)?;
+    parse_payload(&message.payload)
+}
+
+/// Parse `payload`, which must be in its canonical encoding.
+fn parse_payload<'a, T>(payload: &'a [u8]) -> Result<T>
+where
+    T: Deserialize<'a> + Serialize,
 {
-    ensure!(
-        !USED_MESSAGE_IDS.has(deps.storage, message_id),
-        "previously used message_id"
-    );
-    ensure!(is_trusted(deps, &info.sender)?, "forbidden");
-    ensure!(
-        is_signed(deps, message_id, raw_json, signatures)?,
-        "unauthorized"
-    );
-    Ok(serde_json::from_str(raw_json)?)
+    let msg = serde_json::from_slice(payload)?;
+    if canonical_payload(&msg)? != payload {
+        return Err(ContractError::NonCanonicalPayload {});
+    }
+    Ok(msg)
 }
@@ -0,0 +1,407 @@
+//! End-to-end flows through cw-multi-test, with a real bank and a mock job target.
+use anyhow::Result;
+use cosmwasm_std::{coin, to_json_binary, Addr, Coin, Decimal, Empty, Uint128};
+use cw_multi_test::{App, AppResponse, ContractWrapper, Executor};
+use secp256k1::rand::thread_rng;
+use secp256k1::SecretKey;
+use serde::de::DeserializeOwned;
+use turnstone::contract::{execute, instantiate, query, reply, sudo};
+use turnstone::error::ContractError;
+use turnstone::msg::{
+    ConsensusMsg, DepositInfoResponse, ExecuteMsg, InstantiateMsg, JobId, JobInfo, JobInfoResponse,
+    QueryMsg, RelayerRewardsResponse, SignedMessage, SudoMsg, Validator,
+};
+use turnstone::signer;
+
+const DENOM: &str = "uluna";
+
+/// A contract standing in for the targets of jobs, which records every call it gets.
+mod target {
+    use cosmwasm_std::{
+        to_json_binary, Addr, Binary, Deps, DepsMut, Empty, Env, MessageInfo, Response, StdError,
+        StdResult,
+    };
+    use cw_storage_plus::Item;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    pub const CALLS: Item<Vec<(Addr, String)>> = Item::new("calls");
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ExecuteMsg {
+        Record { note: String },
+        Fail {},
+    }
+
+    pub fn instantiate(
+        deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        _msg: Empty,
+    ) -> StdResult<Response> {
+        CALLS.save(deps.storage, &vec![])?;
+        Ok(Response::new())
+    }
+
+    pub fn execute(
+        deps: DepsMut,
+        _env: Env,
+        info: MessageInfo,
+        msg: ExecuteMsg,
+    ) -> StdResult<Response> {
+        match msg {
+            ExecuteMsg::Record { note } => {
+                let mut calls = CALLS.load(deps.storage)?;
+                calls.push((info.sender, note));
+                CALLS.save(deps.storage, &calls)?;
+                Ok(Response::new())
+            }
+            ExecuteMsg::Fail {} => Err(StdError::generic_err("target failed")),
+        }
+    }
+
+    pub fn query(deps: Deps, _env: Env, _msg: Empty) -> StdResult<Binary> {
+        to_json_binary(&CALLS.load(deps.storage)?)
+    }
+}
+
+struct Suite {
+    app: App,
+    turnstone: Addr,
+    target: Addr,
+    relayer: Addr,
+    keys: Vec<SecretKey>,
+    next_message_id: u64,
+}
+
+impl Suite {
+    /// A turnstone with a single validator relayed for by `relayer`, and a target,
+    /// with `balances` in the bank.
+    fn new(balances: &[(&str, u128)]) -> Result<Self> {
+        let mut app = App::new(|router, _, storage| {
+            for (address, amount) in balances {
+                router
+                    .bank
+                    .init_balance(
+                        storage,
+                        &Addr::unchecked(*address),
+                        vec![coin(*amount, DENOM)],
+                    )
+                    .unwrap();
+            }
+        });
+        let turnstone_code = app.store_code(Box::new(
+            ContractWrapper::new(execute, instantiate, query)
+                .with_sudo(sudo)
+                .with_reply(reply),
+        ));
+        let target_code = app.store_code(Box::new(ContractWrapper::new(
+            target::execute,
+            target::instantiate,
+            target::query,
+        )));
+
+        let relayer = Addr::unchecked("relayer");
+        let keys = vec![SecretKey::new(&mut thread_rng())];
+        let turnstone = app.instantiate_contract(
+            turnstone_code,
+            Addr::unchecked("creator"),
+            &InstantiateMsg {
+                valset: vec![Validator {
+                    public_key: signer::public_key(&keys[0]),
+                    stake: Uint128::new(1),
+                    address: vec![relayer.clone()],
+                }],
+                threshold: None,
+                denoms: None,
+            },
+            &[],
+            "turnstone",
+            None,
+        )?;
+        let target = app.instantiate_contract(
+            target_code,
+            Addr::unchecked("creator"),
+            &Empty {},
+            &[],
+            "target",
+            None,
+        )?;
+        Ok(Suite {
+            app,
+            turnstone,
+            target,
+            relayer,
+            keys,
+            next_message_id: 0,
+        })
+    }
+
+    /// Relay `msg`, signed by the valset, under a fresh message id.
+    fn consensus(&mut self, msg: &ConsensusMsg) -> Result<AppResponse> {
+        self.next_message_id += 1;
+        let msg = signer::with_consensus(&self.keys, &self.next_message_id.to_string(), msg, None)?;
+        self.app
+            .execute_contract(self.relayer.clone(), self.turnstone.clone(), &msg, &[])
+    }
+
+    fn execute_job(&mut self, job_id: &str, msg: &target::ExecuteMsg) -> Result<AppResponse> {
+        self.consensus(&ConsensusMsg::ExecuteJob {
+            job_id: JobId(job_id.to_string()),
+            contract: self.target.clone(),
+            msg: to_json_binary(msg)?,
+        })
+    }
+
+    fn deposit(&mut self, from: &str, job_id: &str, amount: u128) -> Result<AppResponse> {
+        self.app.execute_contract(
+            Addr::unchecked(from),
+            self.turnstone.clone(),
+            &ExecuteMsg::Deposit {
+                job_id: JobId(job_id.to_string()),
+            },
+            &[coin(amount, DENOM)],
+        )
+    }
+
+    fn withdraw(&mut self, from: &str, job_id: &str, amount: u128) -> Result<AppResponse> {
+        self.app.execute_contract(
+            Addr::unchecked(from),
+            self.turnstone.clone(),
+            &ExecuteMsg::Withdraw {
+                withdraw_info: vec![JobInfo {
+                    coin: coin(amount, DENOM),
+                    job_id: JobId(job_id.to_string()),
+                }],
+            },
+            &[],
+        )
+    }
+
+    fn query<T: DeserializeOwned>(&self, msg: &QueryMsg) -> Result<T> {
+        Ok(self
+            .app
+            .wrap()
+            .query_wasm_smart(self.turnstone.clone(), msg)?)
+    }
+
+    fn job_funds(&self, job_id: &str) -> Result<Vec<Coin>> {
+        let res: JobInfoResponse = self.query(&QueryMsg::GetJobInfo {
+            job_id: JobId(job_id.to_string()),
+        })?;
+        Ok(res.funds)
+    }
+
+    fn balance(&self, address: impl Into<String>) -> Result<Uint128> {
+        Ok(self.app.wrap().query_balance(address, DENOM)?.amount)
+    }
+
+    fn target_calls(&self) -> Result<Vec<(Addr, String)>> {
+        Ok(self
+            .app
+            .wrap()
+            .query_wasm_smart(self.target.clone(), &Empty {})?)
+    }
+}
+
+#[test]
+fn deposit_execute_job_withdraw() -> Result<()> {
+    let mut suite = Suite::new(&[("alice", 1000)])?;
+    suite.consensus(&ConsensusMsg::SetRelayerFee {
+        fee: Some(coin(10, DENOM)),
+    })?;
+
+    suite.deposit("alice", "1", 100)?;
+    assert_eq!(suite.balance("alice")?, Uint128::new(900));
+    assert_eq!(suite.balance(&suite.turnstone)?, Uint128::new(100));
+
+    // The target is called by the contract, and the relayer fee taken from the job.
+    suite.execute_job(
+        "1",
+        &target::ExecuteMsg::Record {
+            note: "hello".to_string(),
+        },
+    )?;
+    assert_eq!(
+        suite.target_calls()?,
+        vec![(suite.turnstone.clone(), "hello".to_string())]
+    );
+    assert_eq!(suite.job_funds("1")?, vec![coin(90, DENOM)]);
+    let rewards: RelayerRewardsResponse = suite.query(&QueryMsg::GetRelayerRewards {
+        address: suite.relayer.clone(),
+    })?;
+    assert_eq!(rewards.rewards, vec![coin(10, DENOM)]);
+
+    // Both the relayer and the depositor are paid out in tokens.
+    suite.app.execute_contract(
+        suite.relayer.clone(),
+        suite.turnstone.clone(),
+        &ExecuteMsg::ClaimRewards {},
+        &[],
+    )?;
+    assert_eq!(suite.balance(&suite.relayer)?, Uint128::new(10));
+    suite.withdraw("alice", "1", 90)?;
+    assert_eq!(suite.balance("alice")?, Uint128::new(990));
+    assert_eq!(suite.balance(&suite.turnstone)?, Uint128::zero());
+    let deposits: DepositInfoResponse = suite.query(&QueryMsg::GetDepositInfo {
+        address: Addr::unchecked("alice"),
+        start_after: None,
+        limit: None,
+    })?;
+    assert_eq!(deposits.deposits, vec![]);
+
+    Ok(())
+}
+
+#[test]
+fn failed_jobs_roll_back() -> Result<()> {
+    let mut suite = Suite::new(&[("alice", 1000)])?;
+    suite.consensus(&ConsensusMsg::SetRelayerFee {
+        fee: Some(coin(10, DENOM)),
+    })?;
+    suite.deposit("alice", "1", 100)?;
+
+    // A failing target undoes the fee along with the rest of the transaction.
+    assert!(suite
+        .execute_job("1", &target::ExecuteMsg::Fail {})
+        .is_err());
+    assert_eq!(suite.job_funds("1")?, vec![coin(100, DENOM)]);
+    assert!(suite.target_calls()?.is_empty());
+
+    // Jobs that can't pay the fee don't run at all.
+    let err = suite
+        .execute_job(
+            "2",
+            &target::ExecuteMsg::Record {
+                note: "unfunded".to_string(),
+            },
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.root_cause().to_string(),
+        ContractError::InsufficientBalance {
+            job_id: JobId("2".to_string()),
+            denom: DENOM.to_string(),
+            available: Uint128::zero(),
+            requested: Uint128::new(10),
+        }
+        .to_string()
+    );
+    assert!(suite.target_calls()?.is_empty());
+
+    // Withdrawing more than was deposited moves no tokens.
+    assert!(suite.withdraw("alice", "1", 101).is_err());
+    assert_eq!(suite.balance("alice")?, Uint128::new(900));
+
+    Ok(())
+}
+
+#[test]
+fn skipped_jobs_roll_back() -> Result<()> {
+    let mut suite = Suite::new(&[("alice", 1000)])?;
+    suite.consensus(&ConsensusMsg::SetRelayerFee {
+        fee: Some(coin(10, DENOM)),
+    })?;
+    suite.deposit("alice", "1", 100)?;
+
+    // In a batch, a failing target that may be skipped undoes only its own job.
+    let job = |message_id: &str, msg: &target::ExecuteMsg| -> Result<SignedMessage> {
+        let msg = ConsensusMsg::ExecuteJob {
+            job_id: JobId("1".to_string()),
+            contract: suite.target.clone(),
+            msg: to_json_binary(msg)?,
+        };
+        Ok(SignedMessage {
+            continue_on_error: true,
+            ..signer::sign_message(&suite.keys, message_id, &msg, None)?
+        })
+    };
+    let messages = vec![
+        job("failing", &target::ExecuteMsg::Fail {})?,
+        job(
+            "recording",
+            &target::ExecuteMsg::Record {
+                note: "hello".to_string(),
+            },
+        )?,
+    ];
+    let retry = job(
+        "failing",
+        &target::ExecuteMsg::Record {
+            note: "again".to_string(),
+        },
+    )?;
+    let res = suite.app.execute_contract(
+        suite.relayer.clone(),
+        suite.turnstone.clone(),
+        &ExecuteMsg::WithConsensusBatch { messages },
+        &[],
+    )?;
+    assert!(res.events.iter().any(|event| event
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "skipped_message_id" && attr.value == "failing")));
+    assert_eq!(
+        suite.target_calls()?,
+        vec![(suite.turnstone.clone(), "hello".to_string())]
+    );
+    assert_eq!(suite.job_funds("1")?, vec![coin(90, DENOM)]);
+    let rewards: RelayerRewardsResponse = suite.query(&QueryMsg::GetRelayerRewards {
+        address: suite.relayer.clone(),
+    })?;
+    assert_eq!(rewards.rewards, vec![coin(10, DENOM)]);
+
+    // Its message id may then be used again.
+    suite.app.execute_contract(
+        suite.relayer.clone(),
+        suite.turnstone.clone(),
+        &ExecuteMsg::WithConsensusBatch {
+            messages: vec![retry],
+        },
+        &[],
+    )?;
+    assert_eq!(suite.job_funds("1")?, vec![coin(80, DENOM)]);
+
+    Ok(())
+}
+
+#[test]
+fn governance_recovery() -> Result<()> {
+    let mut suite = Suite::new(&[("alice", 1000)])?;
+
+    // The valset's keys are lost, so governance installs a new one.
+    let keys = vec![SecretKey::new(&mut thread_rng())];
+    suite.app.wasm_sudo(
+        suite.turnstone.clone(),
+        &SudoMsg::UpdateValset {
+            valset: vec![Validator {
+                public_key: signer::public_key(&keys[0]),
+                stake: Uint128::new(1),
+                address: vec![suite.relayer.clone()],
+            }],
+        },
+    )?;
+    assert!(suite
+        .consensus(&ConsensusMsg::SetRelayerFee { fee: None })
+        .is_err());
+    suite.keys = keys;
+    suite.consensus(&ConsensusMsg::SetRelayerFee { fee: None })?;
+
+    suite.app.wasm_sudo(
+        suite.turnstone.clone(),
+        &SudoMsg::SetPaused {
+            deposits: true,
+            withdrawals: false,
+            jobs: false,
+        },
+    )?;
+    assert!(suite.deposit("alice", "1", 100).is_err());
+    assert_eq!(suite.balance("alice")?, Uint128::new(1000));
+
+    let config: turnstone::state::Config = suite.query(&QueryMsg::GetConfig {})?;
+    assert_eq!(config.threshold, Decimal::percent(50));
+    assert!(config.paused.deposits);
+
+    Ok(())
+}